@@ -0,0 +1,352 @@
+//! Companion tool that impersonates a SinoWealth keyboard through the Linux `/dev/uhid` interface.
+//!
+//! The created device exposes the vendor interface with the ISP feature report and switches into a
+//! simulated ISP bootloader when it receives the ISP mode command, re-enumerating as `0603:1020`
+//! just like real hardware does. `sinowealth-kb-tool` built with the `uhid` feature can then be
+//! used against it without any special flags.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    process::ExitCode,
+    thread,
+    time::Duration,
+};
+
+use clap::{arg, value_parser, Command};
+use clap_num::maybe_hex;
+use log::{debug, info};
+use simple_logger::SimpleLogger;
+
+#[allow(dead_code)]
+#[path = "../device_spec.rs"]
+mod device_spec;
+#[allow(dead_code)]
+#[path = "../platform_spec.rs"]
+mod platform_spec;
+#[path = "../simulator.rs"]
+mod simulator;
+
+use device_spec::{DeviceSpec, DEVICES, DEVICE_BASE_SH68F90};
+use platform_spec::{PlatformSpec, PLATFORMS};
+use simulator::{SimulatedBootloader, ERASED_BYTE};
+
+const UHID_PATH: &str = "/dev/uhid";
+
+const UHID_DESTROY: u32 = 1;
+const UHID_GET_REPORT: u32 = 9;
+const UHID_GET_REPORT_REPLY: u32 = 10;
+const UHID_CREATE2: u32 = 11;
+const UHID_SET_REPORT: u32 = 13;
+const UHID_SET_REPORT_REPLY: u32 = 14;
+
+const UHID_DATA_MAX: usize = 4096;
+/// `sizeof(struct uhid_event)`, the largest member being `struct uhid_create2_req`
+const UHID_EVENT_SIZE: usize = 4 + 128 + 64 + 64 + 2 + 2 + 4 * 4 + UHID_DATA_MAX;
+
+const BUS_USB: u16 = 0x03;
+const EIO: u16 = 5;
+
+const REPORT_ID_CMD: u8 = 0x05;
+const REPORT_ID_XFER: u8 = 0x06;
+const CMD_ISP_MODE: u8 = 0x75;
+const CMD_REBOOT: u8 = 0x5a;
+
+const ISP_VENDOR_ID: u16 = 0x0603;
+const ISP_PRODUCT_ID: u16 = 0x1020;
+const ISP_IFACE_NUM: i32 = 0;
+
+/// Report descriptor of the NuPhy Air60 vendor interface (interface 1)
+const KEYBOARD_REPORT_DESCRIPTOR: [u8; 118] = [
+    0x05, 0x01, 0x09, 0x80, 0xa1, 0x01, 0x85, 0x01, 0x19, 0x81, 0x29, 0x83, 0x15, 0x00, 0x25, 0x01,
+    0x75, 0x01, 0x95, 0x03, 0x81, 0x02, 0x95, 0x05, 0x81, 0x01, 0xc0, 0x05, 0x0c, 0x09, 0x01, 0xa1,
+    0x01, 0x85, 0x02, 0x19, 0x00, 0x2a, 0x3c, 0x02, 0x15, 0x00, 0x26, 0x3c, 0x02, 0x75, 0x10, 0x95,
+    0x01, 0x81, 0x00, 0xc0, 0x06, 0x00, 0xff, 0x09, 0x01, 0xa1, 0x01, 0x85, 0x05, 0x19, 0x01, 0x29,
+    0x02, 0x15, 0x00, 0x26, 0xff, 0x00, 0x75, 0x08, 0x95, 0x05, 0xb1, 0x02, 0xc0, 0x05, 0x01, 0x09,
+    0x06, 0xa1, 0x01, 0x85, 0x06, 0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01, 0x75,
+    0x01, 0x95, 0x08, 0x81, 0x02, 0x05, 0x07, 0x19, 0x00, 0x29, 0x9f, 0x15, 0x00, 0x25, 0x01, 0x75,
+    0x01, 0x95, 0xa0, 0x81, 0x02, 0xc0,
+];
+
+/// Report descriptor of the ISP bootloader with a command (5) and a page transfer (6) report
+#[rustfmt::skip]
+fn isp_report_descriptor(page_size: usize) -> Vec<u8> {
+    let xfer_count = ((page_size + 1) as u16).to_le_bytes();
+    vec![
+        0x06, 0x00, 0xff,                       // Usage Page (Vendor Defined 0xFF00)
+        0x09, 0x01,                             // Usage (0x01)
+        0xa1, 0x01,                             // Collection (Application)
+        0x15, 0x00,                             //   Logical Minimum (0)
+        0x26, 0xff, 0x00,                       //   Logical Maximum (255)
+        0x75, 0x08,                             //   Report Size (8)
+        0x85, REPORT_ID_CMD,                    //   Report ID (5)
+        0x09, 0x01,                             //   Usage (0x01)
+        0x95, 0x05,                             //   Report Count (5)
+        0xb1, 0x02,                             //   Feature (Data,Var,Abs)
+        0x85, REPORT_ID_XFER,                   //   Report ID (6)
+        0x09, 0x02,                             //   Usage (0x02)
+        0x96, xfer_count[0], xfer_count[1],     //   Report Count (page_size + 1)
+        0xb1, 0x02,                             //   Feature (Data,Var,Abs)
+        0xc0,                                   // End Collection
+    ]
+}
+
+enum UhidEvent {
+    GetReport { id: u32, report_id: u8 },
+    SetReport { id: u32, data: Vec<u8> },
+    Other(u32),
+}
+
+struct UhidDevice {
+    file: File,
+}
+
+impl UhidDevice {
+    fn open() -> Result<Self, io::Error> {
+        let file = OpenOptions::new().read(true).write(true).open(UHID_PATH)?;
+        Ok(Self { file })
+    }
+
+    fn create(
+        &mut self,
+        name: &str,
+        vendor_id: u16,
+        product_id: u16,
+        interface_number: i32,
+        descriptor: &[u8],
+    ) -> Result<(), io::Error> {
+        info!(
+            "Creating {:04x}:{:04x} {} (interface {})",
+            vendor_id, product_id, name, interface_number
+        );
+        let mut event = vec![0u8; UHID_EVENT_SIZE];
+        event[0..4].copy_from_slice(&UHID_CREATE2.to_le_bytes());
+        let name = name.as_bytes();
+        event[4..4 + name.len()].copy_from_slice(name);
+        // the kernel names the interface of a USB HID device the same way
+        let phys = format!("usb-sinowealth-kb-uhid/input{}", interface_number);
+        event[4 + 128..4 + 128 + phys.len()].copy_from_slice(phys.as_bytes());
+        let offset = 4 + 128 + 64 + 64;
+        event[offset..offset + 2].copy_from_slice(&(descriptor.len() as u16).to_le_bytes());
+        event[offset + 2..offset + 4].copy_from_slice(&BUS_USB.to_le_bytes());
+        event[offset + 4..offset + 8].copy_from_slice(&(vendor_id as u32).to_le_bytes());
+        event[offset + 8..offset + 12].copy_from_slice(&(product_id as u32).to_le_bytes());
+        let offset = offset + 20;
+        event[offset..offset + descriptor.len()].copy_from_slice(descriptor);
+        self.file.write_all(&event)
+    }
+
+    fn destroy(&mut self) -> Result<(), io::Error> {
+        let mut event = vec![0u8; UHID_EVENT_SIZE];
+        event[0..4].copy_from_slice(&UHID_DESTROY.to_le_bytes());
+        self.file.write_all(&event)
+    }
+
+    fn read_event(&mut self) -> Result<UhidEvent, io::Error> {
+        let mut event = vec![0u8; UHID_EVENT_SIZE];
+        let size = self.file.read(&mut event)?;
+        if size < 4 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let event_type = u32::from_le_bytes(event[0..4].try_into().unwrap());
+        let id = u32::from_le_bytes(event[4..8].try_into().unwrap());
+        Ok(match event_type {
+            UHID_GET_REPORT => UhidEvent::GetReport {
+                id,
+                report_id: event[8],
+            },
+            UHID_SET_REPORT => {
+                let size = u16::from_le_bytes(event[10..12].try_into().unwrap()) as usize;
+                UhidEvent::SetReport {
+                    id,
+                    data: event[12..12 + size.min(UHID_DATA_MAX)].to_vec(),
+                }
+            }
+            event_type => UhidEvent::Other(event_type),
+        })
+    }
+
+    fn reply_get_report(&mut self, id: u32, data: Result<&[u8], u16>) -> Result<(), io::Error> {
+        let mut event = vec![0u8; UHID_EVENT_SIZE];
+        event[0..4].copy_from_slice(&UHID_GET_REPORT_REPLY.to_le_bytes());
+        event[4..8].copy_from_slice(&id.to_le_bytes());
+        match data {
+            Ok(data) => {
+                event[10..12].copy_from_slice(&(data.len() as u16).to_le_bytes());
+                event[12..12 + data.len()].copy_from_slice(data);
+            }
+            Err(err) => event[8..10].copy_from_slice(&err.to_le_bytes()),
+        }
+        self.file.write_all(&event)
+    }
+
+    fn reply_set_report(&mut self, id: u32, err: u16) -> Result<(), io::Error> {
+        let mut event = vec![0u8; UHID_EVENT_SIZE];
+        event[0..4].copy_from_slice(&UHID_SET_REPORT_REPLY.to_le_bytes());
+        event[4..8].copy_from_slice(&id.to_le_bytes());
+        event[8..10].copy_from_slice(&err.to_le_bytes());
+        self.file.write_all(&event)
+    }
+}
+
+enum Mode {
+    Keyboard,
+    Isp(SimulatedBootloader),
+}
+
+fn cli() -> Command {
+    Command::new("sinowealth-kb-uhid")
+        .about("Impersonate a SinoWealth ISP device through /dev/uhid.")
+        .version(env!("CARGO_PKG_VERSION"))
+        .arg(arg!(flash_file: <FLASH_FILE> "physical flash image backing the device"))
+        .arg(
+            arg!(-d --device <DEVICE>)
+                .required_unless_present_all(["vendor_id", "product_id"])
+                .conflicts_with_all(["platform", "vendor_id", "product_id", "isp_iface_num"])
+                .value_parser(DeviceSpec::available_devices()),
+        )
+        .arg(
+            arg!(-p --platform <PLATFORM>)
+                .value_parser(PlatformSpec::available_platforms())
+                .default_value("sh68f90"),
+        )
+        .arg(
+            arg!(--vendor_id <VID>)
+                .requires("product_id")
+                .value_parser(maybe_hex::<u16>),
+        )
+        .arg(
+            arg!(--product_id <PID>)
+                .requires("vendor_id")
+                .value_parser(maybe_hex::<u16>),
+        )
+        .arg(arg!(--isp_iface_num <NUM>).value_parser(value_parser!(i32)))
+}
+
+fn main() -> ExitCode {
+    match err_main() {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{:}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn err_main() -> Result<(), io::Error> {
+    SimpleLogger::new()
+        .with_utc_timestamps()
+        .with_level(log::LevelFilter::Info)
+        .env()
+        .init()
+        .unwrap();
+
+    let matches = cli().get_matches();
+
+    let flash_file = matches.get_one::<String>("flash_file").unwrap();
+
+    let (vendor_id, product_id, isp_iface_num, platform) = match matches.get_one::<String>("device")
+    {
+        Some(device_name) => {
+            let device_spec = DEVICES.get(device_name).unwrap();
+            (
+                device_spec.vendor_id,
+                device_spec.product_id,
+                device_spec.isp_iface_num,
+                device_spec.platform,
+            )
+        }
+        None => (
+            *matches.get_one::<u16>("vendor_id").unwrap(),
+            *matches.get_one::<u16>("product_id").unwrap(),
+            matches
+                .get_one::<i32>("isp_iface_num")
+                .copied()
+                .unwrap_or(DEVICE_BASE_SH68F90.isp_iface_num),
+            PLATFORMS[matches.get_one::<String>("platform").unwrap()],
+        ),
+    };
+
+    let mut flash = fs::read(flash_file)?;
+    flash.resize(
+        platform.firmware_size + platform.bootloader_size,
+        ERASED_BYTE,
+    );
+
+    let mut uhid = UhidDevice::open()?;
+    uhid.create(
+        "SinoWealth Keyboard",
+        vendor_id,
+        product_id,
+        isp_iface_num,
+        &KEYBOARD_REPORT_DESCRIPTOR,
+    )?;
+    let mut mode = Mode::Keyboard;
+
+    loop {
+        match uhid.read_event()? {
+            UhidEvent::SetReport { id, data } => match mode {
+                Mode::Keyboard => {
+                    uhid.reply_set_report(id, 0)?;
+                    if data.len() > 1 && data[0] == REPORT_ID_CMD && data[1] == CMD_ISP_MODE {
+                        info!("Switching to ISP mode");
+                        uhid.destroy()?;
+                        thread::sleep(Duration::from_millis(500));
+                        uhid.create(
+                            "SinoWealth ISP",
+                            ISP_VENDOR_ID,
+                            ISP_PRODUCT_ID,
+                            ISP_IFACE_NUM,
+                            &isp_report_descriptor(platform.page_size),
+                        )?;
+                        mode = Mode::Isp(SimulatedBootloader::with_flash(
+                            flash.clone(),
+                            platform.firmware_size,
+                        ));
+                    }
+                }
+                Mode::Isp(ref mut bootloader) => {
+                    debug!("SET_REPORT {:02x?}", &data[..data.len().min(8)]);
+                    match bootloader.set_feature_report(&data) {
+                        Ok(()) => uhid.reply_set_report(id, 0)?,
+                        Err(err) => {
+                            info!("Rejected report: {}", err);
+                            uhid.reply_set_report(id, EIO)?;
+                        }
+                    }
+                    flash = bootloader.flash().to_vec();
+                    fs::write(flash_file, &flash)?;
+
+                    if data.len() > 1 && data[0] == REPORT_ID_CMD && data[1] == CMD_REBOOT {
+                        info!("Rebooting into firmware");
+                        uhid.destroy()?;
+                        thread::sleep(Duration::from_millis(500));
+                        uhid.create(
+                            "SinoWealth Keyboard",
+                            vendor_id,
+                            product_id,
+                            isp_iface_num,
+                            &KEYBOARD_REPORT_DESCRIPTOR,
+                        )?;
+                        mode = Mode::Keyboard;
+                    }
+                }
+            },
+            UhidEvent::GetReport { id, report_id } => match mode {
+                Mode::Isp(ref mut bootloader) if report_id == REPORT_ID_XFER => {
+                    let mut buf = vec![0u8; platform.page_size + 2];
+                    buf[0] = REPORT_ID_XFER;
+                    match bootloader.get_feature_report(&mut buf) {
+                        Ok(size) => uhid.reply_get_report(id, Ok(&buf[..size]))?,
+                        Err(err) => {
+                            info!("Rejected report: {}", err);
+                            uhid.reply_get_report(id, Err(EIO))?;
+                        }
+                    }
+                }
+                _ => uhid.reply_get_report(id, Err(EIO))?,
+            },
+            UhidEvent::Other(event_type) => debug!("Ignoring event {}", event_type),
+        }
+    }
+}
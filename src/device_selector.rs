@@ -1,5 +1,5 @@
 use core::time;
-use std::{ffi::CStr, thread, time::Duration};
+use std::{ffi::CStr, rc::Rc, thread, time::Duration};
 
 use hidapi::{BusType, DeviceInfo, HidDevice, HidError, MAX_REPORT_DESCRIPTOR_SIZE};
 use hidparser::parse_report_descriptor;
@@ -10,7 +10,10 @@ use thiserror::Error;
 
 use crate::{
     hid_tree::{DeviceNode, InterfaceNode},
-    is_expected_error, DeviceSpec, ISPDevice,
+    is_expected_error,
+    trace::{Direction, TraceRecorder},
+    transport::HidTransport,
+    DeviceSpec, ISPDevice,
 };
 
 #[cfg(any(target_os = "macos", target_os = "windows"))]
@@ -28,6 +31,9 @@ const GAMING_KB_IFACE: i32 = 0;
 
 const COMMAND_LENGTH: usize = 6;
 
+pub const ISP_MODE_REPORT: [u8; COMMAND_LENGTH] =
+    [REPORT_ID_ISP, CMD_ISP_MODE, 0x00, 0x00, 0x00, 0x00];
+
 #[derive(Debug, Error)]
 pub enum DeviceSelectorError {
     #[error("Device not found")]
@@ -42,6 +48,7 @@ pub enum DeviceSelectorError {
 
 pub struct DeviceSelector {
     api: hidapi::HidApi,
+    recorder: Option<Rc<TraceRecorder>>,
 }
 
 impl DeviceSelector {
@@ -51,14 +58,25 @@ impl DeviceSelector {
         #[cfg(target_os = "macos")]
         api.set_open_exclusive(false); // macOS will throw a privilege violation error otherwise
 
-        Ok(Self { api })
+        Ok(Self {
+            api,
+            recorder: None,
+        })
+    }
+
+    /// Records the ISP mode switch, which is sent before there is an ISP device to record
+    pub fn with_recorder(self, recorder: Rc<TraceRecorder>) -> Self {
+        Self {
+            recorder: Some(recorder),
+            ..self
+        }
     }
 
     fn sorted_usb_device_list(&self) -> Vec<&DeviceInfo> {
         let mut devices: Vec<_> = self
             .api
             .device_list()
-            .filter(|d| d.bus_type() as u32 == BusType::Usb as u32)
+            .filter(|d| is_usb_device(d))
             .collect();
         // TODO: move out the platform specific sorting
         devices.sort_by_key(|d| {
@@ -66,7 +84,7 @@ impl DeviceSelector {
             return (
                 d.vendor_id(),
                 d.product_id(),
-                d.interface_number(),
+                usb_interface_number(d),
                 d.path(),
                 d.usage_page(),
                 d.usage(),
@@ -75,7 +93,7 @@ impl DeviceSelector {
             return (
                 d.vendor_id(),
                 d.product_id(),
-                d.interface_number(),
+                usb_interface_number(d),
                 d.path(),
             );
         });
@@ -88,7 +106,7 @@ impl DeviceSelector {
             (
                 d.vendor_id(),
                 d.product_id(),
-                d.interface_number(),
+                usb_interface_number(d),
                 d.path(),
             )
         });
@@ -234,7 +252,7 @@ impl DeviceSelector {
                         d.product_id(),
                         GAMING_KB_PRODUCT_ID | GAMING_KB_V2_PRODUCT_ID
                     )
-                    && d.interface_number() == GAMING_KB_IFACE
+                    && usb_interface_number(d) == GAMING_KB_IFACE
             })
             .collect();
 
@@ -256,7 +274,10 @@ impl DeviceSelector {
                 .open_path(device.path())
                 .map_err(DeviceSelectorError::from)?;
 
-            Ok(ISPDevice::new(device_spec, handle))
+            Ok(ISPDevice::new(
+                device_spec,
+                Box::new(HidTransport::new(handle)),
+            ))
         };
 
         #[cfg(target_os = "windows")]
@@ -281,7 +302,10 @@ impl DeviceSelector {
                 .open_path(xfer_device.path())
                 .map_err(DeviceSelectorError::from)?;
 
-            Ok(ISPDevice::new(device_spec, cmd_handle, xfer_handle))
+            Ok(ISPDevice::new(
+                device_spec,
+                Box::new(HidTransport::new(cmd_handle, xfer_handle)),
+            ))
         };
     }
 
@@ -289,7 +313,7 @@ impl DeviceSelector {
         let filtered_devices = self.unique_usb_device_list().into_iter().filter(|d| {
             d.vendor_id() == device_spec.vendor_id
                 && d.product_id() == device_spec.product_id
-                && d.interface_number() == device_spec.isp_iface_num
+                && usb_interface_number(d) == device_spec.isp_iface_num
         });
 
         let mut cmd_device_info: Option<&DeviceInfo> = None;
@@ -347,6 +371,24 @@ impl DeviceSelector {
         Ok(isp_device)
     }
 
+    /// Describes how `try_fetch_isp_device` connects to a device, for dry runs
+    pub fn describe_isp_switch(device_spec: DeviceSpec, retries: usize) -> String {
+        format!(
+            "Look for {:04x}:{:04x} (isp_iface_num={} isp_report_id={}), switch it to ISP mode (report {:02x} {:02x} 00 00 00 00) and connect to the bootloader at {:04x}:{:04x} or {:04x}:{:04x}, in up to {} attempts",
+            device_spec.vendor_id,
+            device_spec.product_id,
+            device_spec.isp_iface_num,
+            device_spec.isp_report_id,
+            REPORT_ID_ISP,
+            CMD_ISP_MODE,
+            GAMING_KB_VENDOR_ID,
+            GAMING_KB_PRODUCT_ID,
+            GAMING_KB_VENDOR_ID,
+            GAMING_KB_V2_PRODUCT_ID,
+            retries
+        )
+    }
+
     pub fn try_fetch_isp_device(
         &mut self,
         device_spec: DeviceSpec,
@@ -411,8 +453,11 @@ impl DeviceSelector {
     }
 
     fn enter_isp_mode(&self, handle: &HidDevice) -> Result<(), DeviceSelectorError> {
-        let cmd: [u8; COMMAND_LENGTH] = [REPORT_ID_ISP, CMD_ISP_MODE, 0x00, 0x00, 0x00, 0x00];
-        handle.send_feature_report(&cmd)?;
+        let result = handle.send_feature_report(&ISP_MODE_REPORT);
+        if let Some(recorder) = &self.recorder {
+            recorder.record(Direction::Set, &ISP_MODE_REPORT, &result);
+        }
+        result?;
         Ok(())
     }
 
@@ -434,7 +479,7 @@ impl DeviceSelector {
             let mut manufacturer_string: Option<String> = None;
             let mut product_string: Option<String> = None;
 
-            let path_chunks = devices.chunk_by(|d| (d.path(), d.interface_number()));
+            let path_chunks = devices.chunk_by(|d| (d.path(), usb_interface_number(d)));
 
             for (key, devices) in &path_chunks {
                 let (path, interface_number) = key;
@@ -497,6 +542,44 @@ impl DeviceSelector {
     }
 }
 
+/// hidapi reads the bus type from the USB parent, which devices created through /dev/uhid lack. The
+/// kernel still reports the bus they were created with in HID_ID.
+fn is_usb_device(d: &DeviceInfo) -> bool {
+    #[cfg(all(target_os = "linux", feature = "uhid"))]
+    if d.bus_type() as u32 == BusType::Unknown as u32 {
+        return hid_uevent(d, "HID_ID").is_some_and(|id| id.starts_with("0003:"));
+    }
+    d.bus_type() as u32 == BusType::Usb as u32
+}
+
+/// hidapi reads the interface number from the USB parent as well. For devices created through
+/// /dev/uhid it is taken from the `input<N>` suffix of HID_PHYS, like the kernel names a real USB
+/// HID interface.
+fn usb_interface_number(d: &DeviceInfo) -> i32 {
+    #[cfg(all(target_os = "linux", feature = "uhid"))]
+    if d.interface_number() == -1 {
+        if let Some(interface_number) =
+            hid_uevent(d, "HID_PHYS").and_then(|phys| phys.rsplit_once("/input")?.1.parse().ok())
+        {
+            return interface_number;
+        }
+    }
+    d.interface_number()
+}
+
+#[cfg(all(target_os = "linux", feature = "uhid"))]
+fn hid_uevent(d: &DeviceInfo, key: &str) -> Option<String> {
+    let node = std::path::Path::new(d.path().to_str().ok()?).file_name()?;
+    let uevent_path = std::path::Path::new("/sys/class/hidraw")
+        .join(node)
+        .join("device/uevent");
+    let uevent = std::fs::read_to_string(uevent_path).ok()?;
+    uevent
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        .map(str::to_string)
+}
+
 trait PlatformSpecificInfo {
     fn info(&self) -> String;
 }
@@ -0,0 +1,174 @@
+use std::{cell::Cell, str::FromStr, thread, time::Duration};
+
+use hidapi::HidError;
+use log::info;
+use thiserror::Error;
+
+use crate::transport::ISPTransport;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultKind {
+    /// The report is never delivered and an error is returned instead
+    Drop,
+    /// The report is delivered after a delay
+    Delay(Duration),
+    /// The report is delivered with its payload altered
+    Corrupt,
+    /// The device goes away, this and all subsequent reports fail
+    Disconnect,
+}
+
+/// A fault triggered on the n-th (1-based) feature report going through the transport
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    pub report: usize,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum FaultParseError {
+    #[error(
+        "Invalid fault specification `{0}`, expected <drop|delay:MS|corrupt|disconnect>@<REPORT>"
+    )]
+    InvalidSpec(String),
+}
+
+impl FromStr for Fault {
+    type Err = FaultParseError;
+
+    /// Parses specifications like `drop@17`, `delay:500@3`, `corrupt@5` or `disconnect@10`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = || FaultParseError::InvalidSpec(spec.to_string());
+        let (kind, report) = spec.split_once('@').ok_or_else(invalid)?;
+        let report = report.parse::<usize>().map_err(|_| invalid())?;
+        if report == 0 {
+            return Err(invalid());
+        }
+        let kind = match kind.split_once(':') {
+            Some(("delay", ms)) => {
+                FaultKind::Delay(Duration::from_millis(ms.parse().map_err(|_| invalid())?))
+            }
+            None if kind == "drop" => FaultKind::Drop,
+            None if kind == "corrupt" => FaultKind::Corrupt,
+            None if kind == "disconnect" => FaultKind::Disconnect,
+            _ => return Err(invalid()),
+        };
+        Ok(Fault { kind, report })
+    }
+}
+
+/// Transport wrapper that injects faults into the feature report stream
+pub struct FaultInjector {
+    inner: Box<dyn ISPTransport>,
+    faults: Vec<Fault>,
+    report_count: Cell<usize>,
+    disconnected: Cell<bool>,
+}
+
+impl FaultInjector {
+    pub fn new(inner: Box<dyn ISPTransport>, faults: Vec<Fault>) -> Self {
+        Self {
+            inner,
+            faults,
+            report_count: Cell::new(0),
+            disconnected: Cell::new(false),
+        }
+    }
+
+    /// Counts the report and returns the fault that should be applied to it
+    fn next_fault(&self) -> Result<Option<FaultKind>, HidError> {
+        let report = self.report_count.get() + 1;
+        self.report_count.set(report);
+
+        let fault = self
+            .faults
+            .iter()
+            .find(|f| f.report == report)
+            .map(|f| f.kind);
+        if let Some(kind) = fault {
+            info!("Injecting {:?} at report {}", kind, report);
+        }
+
+        match fault {
+            Some(FaultKind::Disconnect) => self.disconnected.set(true),
+            Some(FaultKind::Drop) => return Err(injected_error("report dropped")),
+            Some(FaultKind::Delay(duration)) => thread::sleep(duration),
+            _ => {}
+        }
+        if self.disconnected.get() {
+            return Err(injected_error("device disconnected"));
+        }
+        Ok(fault)
+    }
+}
+
+fn injected_error(message: &str) -> HidError {
+    HidError::HidApiError {
+        message: format!("Injected fault: {}", message),
+    }
+}
+
+impl ISPTransport for FaultInjector {
+    fn send_feature_report(&self, data: &[u8]) -> Result<(), HidError> {
+        match self.next_fault()? {
+            Some(FaultKind::Corrupt) => {
+                let mut data = data.to_vec();
+                // keep the report id intact so the report still reaches the device
+                let last = data.len() - 1;
+                if last > 0 {
+                    data[last] ^= 0xff;
+                }
+                self.inner.send_feature_report(&data)
+            }
+            _ => self.inner.send_feature_report(data),
+        }
+    }
+
+    fn get_feature_report(&self, buf: &mut [u8]) -> Result<usize, HidError> {
+        let fault = self.next_fault()?;
+        let size = self.inner.get_feature_report(buf)?;
+        if fault == Some(FaultKind::Corrupt) && buf.len() > 1 {
+            buf[1] ^= 0xff;
+        }
+        Ok(size)
+    }
+
+    fn finish(&self) -> Result<(), HidError> {
+        self.inner.finish()
+    }
+}
+
+#[test]
+fn test_parse_fault() {
+    assert_eq!(
+        Fault::from_str("drop@17"),
+        Ok(Fault {
+            kind: FaultKind::Drop,
+            report: 17
+        })
+    );
+    assert_eq!(
+        Fault::from_str("delay:250@3"),
+        Ok(Fault {
+            kind: FaultKind::Delay(Duration::from_millis(250)),
+            report: 3
+        })
+    );
+    assert_eq!(
+        Fault::from_str("disconnect@1"),
+        Ok(Fault {
+            kind: FaultKind::Disconnect,
+            report: 1
+        })
+    );
+}
+
+#[test]
+fn test_parse_fault_invalid() {
+    for spec in ["drop", "drop@0", "explode@3", "delay@3", "corrupt:1@3"] {
+        assert_eq!(
+            Fault::from_str(spec),
+            Err(FaultParseError::InvalidSpec(spec.to_string()))
+        );
+    }
+}
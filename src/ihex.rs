@@ -3,12 +3,12 @@ use thiserror::Error;
 
 #[derive(Debug, Error, PartialEq)]
 pub enum UnpackingError {
-    #[error("Unsupported record type")]
-    UnsupportedRecordType(Record),
     #[error("Error while parsing IHEX records")]
     Parsing(#[from] ReaderError),
     #[error("Address {addr:#06x} greater than binary size {size:#06x}")]
     AddressTooHigh { addr: usize, size: usize },
+    #[error("Address {addr:#06x} lower than the base address {base:#06x} and the data does not fit the section when read relative to 0x0000, re-export the file with addresses starting at {base:#06x}")]
+    AddressTooLow { addr: usize, base: usize },
 }
 
 #[derive(Debug, Error, PartialEq)]
@@ -17,61 +17,109 @@ pub enum ConversionError {
     Unpacking(#[from] UnpackingError),
     #[error("Errow while writing IHEX to string {0:?}")]
     Serializing(#[from] WriterError),
+    #[error("Data ending at {addr:#06x} does not fit in 32-bit IHEX addresses")]
+    AddressOutOfRange { addr: u64 },
 }
 
-pub fn to_ihex(byte_array: &[u8]) -> Result<String, ConversionError> {
+/// Serializes data loaded at `base_address`, so records carry the addresses it lives at.
+///
+/// Data at a non-zero base starts with an extended linear address record, further ones follow
+/// at every 64 KB boundary, which data records never cross.
+pub fn to_ihex(byte_array: &[u8], base_address: usize) -> Result<String, ConversionError> {
+    let end_addr = base_address as u64 + byte_array.len() as u64;
+    if end_addr > 1 << 32 {
+        return Err(ConversionError::AddressOutOfRange { addr: end_addr });
+    }
+
     let mut result: Vec<Record> = vec![];
-    for (i, chunk) in byte_array.chunks(16).enumerate() {
+    // readers start from an upper address of 0
+    let mut upper_address = (base_address == 0).then_some(0);
+    let mut pos = 0;
+    while pos < byte_array.len() {
+        let addr = base_address + pos;
+        let len = 16
+            .min(byte_array.len() - pos)
+            .min(0x10000 - (addr & 0xffff));
+        if upper_address != Some(addr >> 16) {
+            upper_address = Some(addr >> 16);
+            result.push(Record::ExtendedLinearAddress((addr >> 16) as u16));
+        }
         result.push(Record::Data {
-            offset: (i as u16) * 16,
-            value: chunk.to_vec(),
+            offset: addr as u16,
+            value: byte_array[pos..pos + len].to_vec(),
         });
+        pos += len;
     }
     result.push(Record::EndOfFile);
     create_object_file_representation(&result).map_err(ConversionError::from)
 }
 
-pub fn from_ihex(ihex_string: &str, max_length: usize) -> Result<Vec<u8>, ConversionError> {
+/// Loads data expected at `base_address`, the result starts with the byte at that address
+pub fn from_ihex(
+    ihex_string: &str,
+    base_address: usize,
+    max_length: usize,
+) -> Result<Vec<u8>, ConversionError> {
     let mut reader = Reader::new(ihex_string);
-    unpack_records(&mut reader, max_length).map_err(ConversionError::from)
+    unpack_records(&mut reader, base_address, max_length).map_err(ConversionError::from)
 }
 
 fn unpack_records(
     records: &mut impl Iterator<Item = Result<Record, ReaderError>>,
+    base_address: usize,
     max_length: usize,
 ) -> Result<Vec<u8>, UnpackingError> {
-    let mut result: Vec<u8> = vec![];
+    let mut chunks: Vec<(usize, Vec<u8>)> = vec![];
+    // set by extended segment (type 02) and extended linear (type 04) address records
+    let mut upper_address = 0;
     for rec in records {
         match rec {
             Ok(rec) => match rec {
                 Record::Data { offset, value } => {
-                    let end_addr = offset as usize + value.len();
-                    if end_addr > max_length {
-                        return Err(UnpackingError::AddressTooHigh {
-                            addr: end_addr,
-                            size: max_length,
-                        });
-                    }
-                    if end_addr > result.len() {
-                        result.resize(end_addr, 0);
-                    }
-
-                    for (n, b) in value.iter().enumerate() {
-                        result[offset as usize + n] = *b;
-                    }
-                }
-                Record::ExtendedSegmentAddress(_base) => {
-                    return Err(UnpackingError::UnsupportedRecordType(rec))
-                }
-                Record::ExtendedLinearAddress(_base) => {
-                    return Err(UnpackingError::UnsupportedRecordType(rec))
+                    chunks.push((upper_address + offset as usize, value));
                 }
+                Record::ExtendedSegmentAddress(segment) => upper_address = (segment as usize) << 4,
+                Record::ExtendedLinearAddress(upper) => upper_address = (upper as usize) << 16,
                 Record::EndOfFile => break,
                 Record::StartLinearAddress(_) | Record::StartSegmentAddress { .. } => {}
             },
             Err(err) => return Err(UnpackingError::Parsing(err)),
         }
     }
+
+    // files written before addresses were relative to the base start at 0x0000, e.g. a bootloader
+    // read by older versions
+    let base_address = match chunks.iter().map(|(addr, _)| *addr).min() {
+        Some(addr) if addr < base_address => {
+            let end_addr = chunks.iter().map(|(addr, value)| addr + value.len()).max();
+            if end_addr.is_some_and(|end_addr| end_addr > max_length) {
+                return Err(UnpackingError::AddressTooLow {
+                    addr,
+                    base: base_address,
+                });
+            }
+            0
+        }
+        _ => base_address,
+    };
+
+    let mut result: Vec<u8> = vec![];
+    for (addr, value) in chunks {
+        let end_addr = addr + value.len();
+        if end_addr > base_address + max_length {
+            return Err(UnpackingError::AddressTooHigh {
+                addr: end_addr,
+                size: base_address + max_length,
+            });
+        }
+        let start = addr - base_address;
+        let end = end_addr - base_address;
+        if end > result.len() {
+            result.resize(end, 0);
+        }
+
+        result[start..end].copy_from_slice(&value);
+    }
     Ok(result)
 }
 
@@ -79,6 +127,7 @@ fn unpack_records(
 fn test_from_ihex() {
     let result = from_ihex(
         ":100000000200660227BD010A32646402CB9053DA13\n:00000001FF",
+        0,
         16,
     )
     .unwrap();
@@ -92,6 +141,7 @@ fn test_from_ihex() {
 fn test_from_ihex_address_start_at_0x0001() {
     let result = from_ihex(
         ":100010000200660227BD010A32646402CB9053DA03\n:00000001FF",
+        0,
         32,
     );
     let mut expected: Vec<u8> = Vec::new();
@@ -106,6 +156,7 @@ fn test_from_ihex_address_start_at_0x0001() {
 fn test_from_ihex_err_checksum_mismatch() {
     let result = from_ihex(
         ":100000000200660227BD010A32646402CB9053DA00\n:00000001FF",
+        0,
         16,
     );
     let expected = Err(ConversionError::Unpacking(UnpackingError::Parsing(
@@ -118,6 +169,7 @@ fn test_from_ihex_err_checksum_mismatch() {
 fn test_from_ihex_err_address_too_high() {
     let result = from_ihex(
         ":100010000200660227BD010A32646402CB9053DA03\n:00000001FF",
+        0,
         16,
     );
     let expected = Err(ConversionError::Unpacking(UnpackingError::AddressTooHigh {
@@ -126,3 +178,140 @@ fn test_from_ihex_err_address_too_high() {
     }));
     assert_eq!(result, expected);
 }
+
+#[test]
+fn test_from_ihex_base_address() {
+    let result = from_ihex(
+        ":10F000000200660227BD010A32646402CB9053DA23\n:00000001FF",
+        0xf000,
+        0x1000,
+    );
+    assert_eq!(
+        result,
+        Ok(vec![
+            2, 0, 102, 2, 39, 189, 1, 10, 50, 100, 100, 2, 203, 144, 83, 218,
+        ])
+    );
+}
+
+#[test]
+fn test_from_ihex_relative_to_base_address() {
+    // a bootloader written with addresses starting at 0x0000
+    let result = from_ihex(
+        ":100000000200660227BD010A32646402CB9053DA13\n:00000001FF",
+        0xf000,
+        0x1000,
+    );
+    assert_eq!(
+        result,
+        Ok(vec![
+            2, 0, 102, 2, 39, 189, 1, 10, 50, 100, 100, 2, 203, 144, 83, 218,
+        ])
+    );
+}
+
+#[test]
+fn test_from_ihex_err_address_too_low() {
+    let result = from_ihex(
+        ":10FFF8000200660227BD010A32646402CB9053DA1C\n:00000001FF",
+        0x10000,
+        0x1000,
+    );
+    let expected = Err(ConversionError::Unpacking(UnpackingError::AddressTooLow {
+        addr: 0xfff8,
+        base: 0x10000,
+    }));
+    assert_eq!(result, expected);
+}
+
+#[test]
+fn test_to_ihex_base_address() {
+    let data = vec![
+        2, 0, 102, 2, 39, 189, 1, 10, 50, 100, 100, 2, 203, 144, 83, 218,
+    ];
+    assert_eq!(
+        to_ihex(&data, 0xf000),
+        Ok(
+            ":020000040000FA\n:10F000000200660227BD010A32646402CB9053DA23\n:00000001FF\n"
+                .to_string()
+        )
+    );
+    assert_eq!(
+        from_ihex(&to_ihex(&data, 0xf000).unwrap(), 0xf000, 0x1000),
+        Ok(data)
+    );
+}
+
+#[test]
+fn test_from_ihex_extended_linear_address() {
+    // Keil emits a type-04 record even for images below 64 KB
+    let result = from_ihex(
+        ":020000040000FA\n:100000000200660227BD010A32646402CB9053DA13\n:00000001FF",
+        0,
+        16,
+    );
+    assert_eq!(
+        result,
+        Ok(vec![
+            2, 0, 102, 2, 39, 189, 1, 10, 50, 100, 100, 2, 203, 144, 83, 218,
+        ])
+    );
+
+    let result = from_ihex(
+        ":020000040001F9\n:100000000200660227BD010A32646402CB9053DA13\n:00000001FF",
+        0x10000,
+        16,
+    );
+    assert_eq!(result.unwrap()[0..3], [2, 0, 102]);
+}
+
+#[test]
+fn test_from_ihex_extended_segment_address() {
+    let result = from_ihex(
+        ":020000020F00ED\n:100000000200660227BD010A32646402CB9053DA13\n:00000001FF",
+        0,
+        0x10000,
+    );
+    let result = result.unwrap();
+    assert_eq!(result.len(), 0xf010);
+    assert_eq!(result[0xf000..0xf003], [2, 0, 102]);
+}
+
+#[test]
+fn test_to_ihex_extended_linear_address() {
+    let data: Vec<u8> = (0..0x20).collect();
+    let ihex = to_ihex(&data, 0xfff8).unwrap();
+    let records: Vec<Record> = Reader::new(&ihex).map(|rec| rec.unwrap()).collect();
+    assert_eq!(
+        records,
+        vec![
+            Record::ExtendedLinearAddress(0x0000),
+            Record::Data {
+                offset: 0xfff8,
+                value: data[0x00..0x08].to_vec()
+            },
+            Record::ExtendedLinearAddress(0x0001),
+            Record::Data {
+                offset: 0x0000,
+                value: data[0x08..0x18].to_vec()
+            },
+            Record::Data {
+                offset: 0x0010,
+                value: data[0x18..0x20].to_vec()
+            },
+            Record::EndOfFile,
+        ]
+    );
+    assert_eq!(from_ihex(&ihex, 0xfff8, 0x20), Ok(data));
+}
+
+#[test]
+fn test_to_ihex_err_address_out_of_range() {
+    assert_eq!(
+        to_ihex(&[0; 0x10], 0xffff_fff8),
+        Err(ConversionError::AddressOutOfRange {
+            addr: 0x1_0000_0008
+        })
+    );
+    assert!(to_ihex(&[0; 0x10], 0xffff_fff0).is_ok());
+}
@@ -1,35 +1,60 @@
 use core::panic;
-use std::{str::FromStr, thread, time};
+use std::{cell::RefCell, str::FromStr, thread, time};
 
 use indicatif::ProgressBar;
 use log::{debug, error};
 use thiserror::Error;
 
-use crate::{device_spec::*, is_expected_error, util, VerificationError};
+use crate::{
+    device_spec::*,
+    is_expected_error,
+    journal::{Journal, JournalError},
+    simulator::ERASED_BYTE,
+    transport::ISPTransport,
+    util, VerificationError,
+};
 
 extern crate hidapi;
 
-use hidapi::{HidDevice, HidError};
+use hidapi::HidError;
+
+#[cfg(test)]
+use crate::{
+    fault_injector::{Fault, FaultInjector},
+    simulator::SimulatedBootloader,
+    transport::SimulatedTransport,
+    util::test_payload,
+};
 
 const COMMAND_LENGTH: usize = 6;
 
-const REPORT_ID_CMD: u8 = 0x05;
-const REPORT_ID_XFER: u8 = 0x06;
+pub(crate) const REPORT_ID_CMD: u8 = 0x05;
+pub(crate) const REPORT_ID_XFER: u8 = 0x06;
+
+pub(crate) const CMD_ENABLE_FIRMWARE: u8 = 0x55;
+pub(crate) const CMD_INIT_READ: u8 = 0x52;
+pub(crate) const CMD_INIT_WRITE: u8 = 0x57;
+pub(crate) const CMD_ERASE: u8 = 0x45;
+pub(crate) const CMD_REBOOT: u8 = 0x5a;
+
+pub(crate) const XFER_READ_PAGE: u8 = 0x72;
+pub(crate) const XFER_WRITE_PAGE: u8 = 0x77;
 
-const CMD_ENABLE_FIRMWARE: u8 = 0x55;
-const CMD_INIT_READ: u8 = 0x52;
-const CMD_INIT_WRITE: u8 = 0x57;
-const CMD_ERASE: u8 = 0x45;
-const CMD_REBOOT: u8 = 0x5a;
+/// The reset vector LJMP written by `erase` to keep the device in ISP mode
+const RESET_VECTOR_LENGTH: usize = 3;
 
-const XFER_READ_PAGE: u8 = 0x72;
-const XFER_WRITE_PAGE: u8 = 0x77;
+/// Delay before the first retry of a page, doubled with every further attempt
+const RETRY_BACKOFF: time::Duration = time::Duration::from_millis(100);
+/// Upper bound of the retry delay, reached after 6 doublings
+const MAX_RETRY_BACKOFF: time::Duration = time::Duration::from_millis(6400);
 
 pub struct ISPDevice {
-    cmd_device: HidDevice,
-    #[cfg(target_os = "windows")]
-    xfer_device: HidDevice,
+    transport: Box<dyn ISPTransport>,
     device_spec: DeviceSpec,
+    verify_erase: bool,
+    read_passes: usize,
+    page_retries: usize,
+    journal: Option<RefCell<Journal>>,
 }
 
 #[derive(Debug, Error)]
@@ -38,8 +63,20 @@ pub enum ISPError {
     HidError(#[from] HidError),
     #[error(transparent)]
     VerificationError(#[from] VerificationError),
+    #[error(transparent)]
+    JournalError(#[from] JournalError),
     #[error("Read/Write operation mistmatch")]
     ReadWriteMismatch,
+    #[error("Unstable read @ {addr:#06x}, no value was read by a majority of passes")]
+    UnstableRead { addr: usize },
+    #[error("Flash is not blank @ {addr:#06x} --- {value:#04x}")]
+    NotBlank { addr: usize, value: u8 },
+    #[error("Address range {start:#06x}-{end:#06x} is outside of flash (size {size:#06x})")]
+    AddressOutOfRange {
+        start: usize,
+        end: usize,
+        size: usize,
+    },
 }
 
 #[derive(Debug, Clone)]
@@ -65,6 +102,16 @@ impl ReadSection {
             ReadSection::Full.to_str(),
         ]
     }
+
+    /// Start address and length of the section in flash
+    pub fn bounds(&self, device_spec: DeviceSpec) -> (usize, usize) {
+        let platform = device_spec.platform;
+        match self {
+            ReadSection::Firmware => (0, platform.firmware_size),
+            ReadSection::Bootloader => (platform.firmware_size, platform.bootloader_size),
+            ReadSection::Full => (0, platform.firmware_size + platform.bootloader_size),
+        }
+    }
 }
 
 impl FromStr for ReadSection {
@@ -80,45 +127,92 @@ impl FromStr for ReadSection {
 }
 
 impl ISPDevice {
-    #[cfg(not(target_os = "windows"))]
-    pub fn new(device_spec: DeviceSpec, device: HidDevice) -> Self {
+    pub fn new(device_spec: DeviceSpec, transport: Box<dyn ISPTransport>) -> Self {
         Self {
-            cmd_device: device,
+            transport,
             device_spec,
+            verify_erase: false,
+            read_passes: 1,
+            page_retries: 0,
+            journal: None,
         }
     }
 
-    #[cfg(target_os = "windows")]
-    pub fn new(device_spec: DeviceSpec, cmd_device: HidDevice, xfer_device: HidDevice) -> Self {
+    /// Records the progress of `write_cycle` in a journal, which is removed once the written
+    /// firmware has been verified and enabled
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(RefCell::new(journal));
+        self
+    }
+
+    /// Retries failed page transfers up to `page_retries` times, continuing from the failing page
+    pub fn with_page_retries(mut self, page_retries: usize) -> Self {
+        self.page_retries = page_retries;
+        self
+    }
+
+    /// Makes every page be read `read_passes` times. Pages with differing passes are read again
+    /// as often and the value read by the majority of passes is used for each byte.
+    pub fn with_read_passes(mut self, read_passes: usize) -> Self {
+        self.read_passes = read_passes.max(1);
+        self
+    }
+
+    /// Makes `write_cycle` read back the firmware section after erasing and fail before writing
+    /// if it is not blank
+    pub fn with_erase_verification(mut self, verify_erase: bool) -> Self {
+        self.verify_erase = verify_erase;
+        self
+    }
+
+    /// Wraps the underlying transport, e.g. to observe or alter the exchanged feature reports
+    pub fn map_transport(
+        self,
+        f: impl FnOnce(Box<dyn ISPTransport>) -> Box<dyn ISPTransport>,
+    ) -> Self {
         Self {
-            cmd_device,
-            xfer_device,
-            device_spec,
+            transport: f(self.transport),
+            ..self
         }
     }
 
+    /// Ends the session, failing if the transport expected more of it, e.g. a replayed trace
+    pub fn finish(&self) -> Result<(), ISPError> {
+        self.transport.finish().map_err(ISPError::from)
+    }
+
     pub fn read_cycle(&self, read_fragment: ReadSection) -> Result<Vec<u8>, ISPError> {
-        self.enable_firmware()?;
+        let (start_addr, length) = read_fragment.bounds(self.device_spec);
 
-        let (start_addr, length) = match read_fragment {
-            ReadSection::Firmware => (0, self.device_spec.platform.firmware_size),
-            ReadSection::Bootloader => (
-                self.device_spec.platform.firmware_size,
-                self.device_spec.platform.bootloader_size,
-            ),
-            ReadSection::Full => (
-                0,
-                self.device_spec.platform.firmware_size + self.device_spec.platform.bootloader_size,
-            ),
-        };
+        self.read_range_cycle(start_addr, length)
+    }
 
-        let firmware = self.read(start_addr, length)?;
+    /// Reads `length` bytes starting at `start_addr` and reboots the device afterwards
+    pub fn read_range_cycle(&self, start_addr: usize, length: usize) -> Result<Vec<u8>, ISPError> {
+        let contents = self.read_range(start_addr, length)?;
 
         if self.device_spec.reboot {
             self.reboot();
         }
 
-        Ok(firmware)
+        Ok(contents)
+    }
+
+    /// Reads `length` bytes starting at `start_addr`, leaving the device in ISP mode.
+    ///
+    /// The bootloader transfers whole pages, so the range is widened to page boundaries for
+    /// reading and trimmed afterwards.
+    pub fn read_range(&self, start_addr: usize, length: usize) -> Result<Vec<u8>, ISPError> {
+        let end_addr = check_flash_range(self.device_spec, start_addr, length)?;
+
+        self.enable_firmware()?;
+
+        let page_size = self.device_spec.platform.page_size;
+        let aligned_start = start_addr - start_addr % page_size;
+        let aligned_end = end_addr.div_ceil(page_size) * page_size;
+        let pages = self.read(aligned_start, aligned_end - aligned_start)?;
+
+        Ok(pages[start_addr - aligned_start..end_addr - aligned_start].to_vec())
     }
 
     pub fn write_cycle(&self, firmware: &mut [u8]) -> Result<(), ISPError> {
@@ -126,12 +220,16 @@ impl ISPDevice {
         firmware.copy_within(1..3, self.device_spec.platform.firmware_size - 4);
 
         self.erase()?;
+        if self.verify_erase {
+            // erasing enables reading, enabling the firmware here would break the write
+            let erased = self.read(0, self.device_spec.platform.firmware_size)?;
+            eprintln!("Verifying erase...");
+            check_blank(&erased)?;
+        }
         self.write(0, firmware)?;
 
         // cleanup the address at <firmware_size-4>
-        firmware[self.device_spec.platform.firmware_size - 4
-            ..self.device_spec.platform.firmware_size - 2]
-            .fill(0);
+        util::clear_reset_vector_copy(firmware, self.device_spec);
 
         let read_back = self.read(0, self.device_spec.platform.firmware_size)?;
 
@@ -144,14 +242,22 @@ impl ISPDevice {
             self.reboot();
         }
 
+        // until the firmware is enabled the device stays in ISP mode and needs the journal
+        if let Some(journal) = &self.journal {
+            journal.borrow().complete()?;
+        }
+
         Ok(())
     }
 
-    fn xfer_device(&self) -> &HidDevice {
-        #[cfg(target_os = "windows")]
-        return &self.xfer_device;
-        #[cfg(not(target_os = "windows"))]
-        &self.cmd_device
+    /// Reads the firmware section and checks that it is erased, apart from the reset vector.
+    ///
+    /// Like the erase verification in `write_cycle` this neither enables the firmware nor
+    /// reboots, so an erased device stays in ISP mode and can still be written.
+    pub fn blank_check(&self) -> Result<(), ISPError> {
+        let firmware = self.read(0, self.device_spec.platform.firmware_size)?;
+        eprintln!("Checking...");
+        check_blank(&firmware)
     }
 
     fn read(&self, start_addr: usize, length: usize) -> Result<Vec<u8>, ISPError> {
@@ -164,19 +270,65 @@ impl ISPDevice {
 
         self.init_read(start_addr)?;
 
+        let mut unstable: Vec<usize> = vec![];
         for i in 0..num_page {
             bar.inc(1);
-            debug!(
-                "Reading page {} @ offset {:#06x}",
-                i,
-                start_addr + i * page_size
-            );
-            self.read_page(&mut result)?;
+            let page_addr = start_addr + i * page_size;
+            debug!("Reading page {} @ offset {:#06x}", i, page_addr);
+            if self.read_passes > 1 {
+                let (page, page_unstable) =
+                    self.retry_page(page_addr, |_| self.read_page_voted(page_addr))?;
+                result.extend_from_slice(&page);
+                unstable.extend(page_unstable);
+            } else {
+                let page = self.retry_page(page_addr, |resume| {
+                    if resume {
+                        self.init_read(page_addr)?;
+                    }
+                    let mut page = vec![];
+                    self.read_page(&mut page)?;
+                    Ok(page)
+                })?;
+                result.extend_from_slice(&page);
+            }
         }
         bar.finish();
+
+        for range in util::address_ranges(&unstable) {
+            eprintln!(
+                "Unstable read @ {:#06x}-{:#06x} ({} bytes)",
+                range.start,
+                range.end - 1,
+                range.len()
+            );
+        }
         Ok(result)
     }
 
+    /// Reads a page `read_passes` times, or twice as often if the passes differ, and returns the
+    /// majority vote along with the addresses that differed between passes
+    fn read_page_voted(&self, page_addr: usize) -> Result<(Vec<u8>, Vec<usize>), ISPError> {
+        let mut passes: Vec<Vec<u8>> = vec![];
+        while passes.len() < self.read_passes * 2 {
+            let mut page = vec![];
+            self.init_read(page_addr)?;
+            self.read_page(&mut page)?;
+            passes.push(page);
+
+            if passes.len() == self.read_passes && passes.iter().all(|p| *p == passes[0]) {
+                break;
+            }
+        }
+        if passes.len() > self.read_passes {
+            debug!(
+                "Page @ {:#06x} differed between passes, read {} times",
+                page_addr,
+                passes.len()
+            );
+        }
+        vote(&passes, page_addr)
+    }
+
     fn write(&self, start_addr: usize, buffer: &[u8]) -> Result<(), ISPError> {
         eprintln!("Writing...");
         let bar = ProgressBar::new(self.device_spec.num_pages() as u64);
@@ -185,13 +337,50 @@ impl ISPDevice {
         let page_size = self.device_spec.platform.page_size;
         for i in 0..self.device_spec.num_pages() {
             bar.inc(1);
-            debug!("Writing page {} @ offset {:#06x}", i, i * page_size);
-            self.write_page(&buffer[(i * page_size)..((i + 1) * page_size)])?;
+            let page_addr = start_addr + i * page_size;
+            debug!("Writing page {} @ offset {:#06x}", i, page_addr);
+            self.retry_page(page_addr, |resume| {
+                if resume {
+                    self.init_write(page_addr)?;
+                }
+                self.write_page(&buffer[(i * page_size)..((i + 1) * page_size)])
+            })?;
+            if let Some(journal) = &self.journal {
+                journal.borrow_mut().record_page(i)?;
+            }
         }
         bar.finish();
         Ok(())
     }
 
+    /// Runs a page transfer, retrying transient errors with an exponential backoff.
+    ///
+    /// The transfer is told when it is being retried, so it can re-initialize the operation at
+    /// the failing page address instead of starting over.
+    fn retry_page<T>(
+        &self,
+        page_addr: usize,
+        mut transfer: impl FnMut(bool) -> Result<T, ISPError>,
+    ) -> Result<T, ISPError> {
+        let mut attempt = 0;
+        loop {
+            match transfer(attempt > 0) {
+                Err(err @ (ISPError::HidError(_) | ISPError::ReadWriteMismatch))
+                    if attempt < self.page_retries =>
+                {
+                    let backoff = retry_backoff(attempt);
+                    attempt += 1;
+                    eprintln!(
+                        "Page @ {:#06x} failed: {}. Retrying in {:?} ({}/{})",
+                        page_addr, err, backoff, attempt, self.page_retries
+                    );
+                    thread::sleep(backoff);
+                }
+                result => return result,
+            }
+        }
+    }
+
     /// Initializes the read operation / sets the initial read address
     fn init_read(&self, start_addr: usize) -> Result<(), ISPError> {
         let cmd: [u8; COMMAND_LENGTH] = [
@@ -202,7 +391,7 @@ impl ISPDevice {
             0,
             0,
         ];
-        self.cmd_device
+        self.transport
             .send_feature_report(&cmd)
             .map_err(ISPError::from)?;
         Ok(())
@@ -218,7 +407,7 @@ impl ISPDevice {
             0,
             0,
         ];
-        self.cmd_device
+        self.transport
             .send_feature_report(&cmd)
             .map_err(ISPError::from)?;
         Ok(())
@@ -229,7 +418,7 @@ impl ISPDevice {
         let page_size = self.device_spec.platform.page_size;
         let mut xfer_buf: Vec<u8> = vec![0; page_size + 2];
         xfer_buf[0] = REPORT_ID_XFER;
-        self.xfer_device()
+        self.transport
             .get_feature_report(&mut xfer_buf)
             .map_err(ISPError::from)?;
         buf.extend_from_slice(&xfer_buf[2..(page_size + 2)]);
@@ -251,7 +440,7 @@ impl ISPDevice {
         xfer_buf[0] = REPORT_ID_XFER;
         xfer_buf[1] = XFER_WRITE_PAGE;
         xfer_buf[2..length].clone_from_slice(buf);
-        self.xfer_device()
+        self.transport
             .send_feature_report(&xfer_buf)
             .map_err(ISPError::from)?;
         if xfer_buf[1] != XFER_WRITE_PAGE {
@@ -265,20 +454,20 @@ impl ISPDevice {
     ///
     /// Side-effect: enables reading the firmware without erasing flash first.
     /// Credits to @gashtaan for finding this out.
-    fn enable_firmware(&self) -> Result<(), ISPError> {
+    pub fn enable_firmware(&self) -> Result<(), ISPError> {
         eprintln!("Enabling firmware...");
         let cmd: [u8; COMMAND_LENGTH] = [REPORT_ID_CMD, CMD_ENABLE_FIRMWARE, 0, 0, 0, 0];
 
-        self.cmd_device.send_feature_report(&cmd)?;
+        self.transport.send_feature_report(&cmd)?;
         Ok(())
     }
 
     /// Erases everything in flash, except the ISP bootloader section itself and initializes the
     /// reset vector to jump to ISP.
-    fn erase(&self) -> Result<(), ISPError> {
+    pub fn erase(&self) -> Result<(), ISPError> {
         eprintln!("Erasing...");
         let cmd: [u8; COMMAND_LENGTH] = [REPORT_ID_CMD, CMD_ERASE, 0, 0, 0, 0];
-        self.cmd_device
+        self.transport
             .send_feature_report(&cmd)
             .map_err(ISPError::from)?;
         thread::sleep(time::Duration::from_millis(2000));
@@ -286,10 +475,10 @@ impl ISPDevice {
     }
 
     /// Causes the device to start running the main firmware
-    fn reboot(&self) {
+    pub fn reboot(&self) {
         eprintln!("Rebooting...");
         let cmd: [u8; COMMAND_LENGTH] = [REPORT_ID_CMD, CMD_REBOOT, 0, 0, 0, 0];
-        if let Err(err) = self.cmd_device.send_feature_report(&cmd) {
+        if let Err(err) = self.transport.send_feature_report(&cmd) {
             debug!("Error: {:}", err);
             if !is_expected_error(&err) {
                 error!("Unexpected error: {:}", err);
@@ -298,3 +487,436 @@ impl ISPDevice {
         thread::sleep(time::Duration::from_millis(2000));
     }
 }
+
+/// Checks that `length` bytes starting at `start_addr` are within flash and returns the end
+/// address of the range
+pub fn check_flash_range(
+    device_spec: DeviceSpec,
+    start_addr: usize,
+    length: usize,
+) -> Result<usize, ISPError> {
+    let flash_size = device_spec.total_flash_size();
+    match start_addr.checked_add(length) {
+        Some(end_addr) if length > 0 && end_addr <= flash_size => Ok(end_addr),
+        end_addr => Err(ISPError::AddressOutOfRange {
+            start: start_addr,
+            end: end_addr.unwrap_or(usize::MAX),
+            size: flash_size,
+        }),
+    }
+}
+
+/// Delay before retrying a page that failed `attempt` times before
+fn retry_backoff(attempt: usize) -> time::Duration {
+    u32::try_from(attempt)
+        .ok()
+        .and_then(|attempt| 2u32.checked_pow(attempt))
+        .and_then(|factor| RETRY_BACKOFF.checked_mul(factor))
+        .map_or(MAX_RETRY_BACKOFF, |backoff| backoff.min(MAX_RETRY_BACKOFF))
+}
+
+/// Picks the value read by the majority of passes for every byte of a page
+fn vote(passes: &[Vec<u8>], page_addr: usize) -> Result<(Vec<u8>, Vec<usize>), ISPError> {
+    let mut page = vec![];
+    let mut unstable = vec![];
+    for i in 0..passes[0].len() {
+        let values: Vec<u8> = passes.iter().map(|p| p[i]).collect();
+        let (value, count) = values
+            .iter()
+            .map(|v| (*v, values.iter().filter(|w| *w == v).count()))
+            .max_by_key(|(_, count)| *count)
+            .unwrap();
+        if count < values.len() {
+            unstable.push(page_addr + i);
+        }
+        if count * 2 <= values.len() {
+            return Err(ISPError::UnstableRead {
+                addr: page_addr + i,
+            });
+        }
+        page.push(value);
+    }
+    Ok((page, unstable))
+}
+
+fn check_blank(firmware: &[u8]) -> Result<(), ISPError> {
+    match firmware
+        .iter()
+        .enumerate()
+        .skip(RESET_VECTOR_LENGTH)
+        .find(|(_, b)| **b != ERASED_BYTE)
+    {
+        Some((addr, value)) => Err(ISPError::NotBlank {
+            addr,
+            value: *value,
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+fn simulated_device(flash: Vec<u8>) -> (ISPDevice, SimulatedTransport) {
+    simulated_device_with_faults(flash, &[])
+}
+
+#[cfg(test)]
+fn simulated_device_with_faults(
+    flash: Vec<u8>,
+    faults: &[&str],
+) -> (ISPDevice, SimulatedTransport) {
+    let device_spec = DeviceSpec {
+        reboot: false,
+        ..DEVICE_BASE_SH68F90
+    };
+    let transport = SimulatedTransport::new(SimulatedBootloader::with_flash(
+        flash,
+        device_spec.platform.firmware_size,
+    ));
+    let faults = faults.iter().map(|f| f.parse::<Fault>().unwrap()).collect();
+    let device = ISPDevice::new(
+        device_spec,
+        Box::new(FaultInjector::new(Box::new(transport.clone()), faults)),
+    );
+    (device, transport)
+}
+
+#[test]
+fn test_write_cycle() {
+    let (device, transport) = simulated_device(vec![0xaa; 65536]);
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+
+    device.write_cycle(&mut firmware).unwrap();
+
+    let flash = transport.flash();
+    assert_eq!(flash[0..3], [0x02, 0xf0, 0x00]);
+    assert_eq!(flash[3..0xeffb], firmware[3..0xeffb]);
+    assert_eq!(flash[0xeffb..0xeffe], [0x02, 0x00, 0x66]);
+    assert!(flash[0xf000..].iter().all(|b| *b == 0xaa));
+}
+
+#[test]
+fn test_write_and_read_cycle() {
+    let (device, _transport) = simulated_device(vec![0xaa; 65536]);
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+
+    device.write_cycle(&mut firmware).unwrap();
+    let read_back = device.read_cycle(ReadSection::Firmware).unwrap();
+
+    assert_eq!(read_back, test_payload(DEVICE_BASE_SH68F90));
+}
+
+#[test]
+fn test_read_cycle_bootloader() {
+    let mut flash = vec![0x00; 0xf000];
+    flash.extend((0..0x1000).map(|i| (i % 256) as u8));
+    let (device, _transport) = simulated_device(flash.clone());
+
+    let bootloader = device.read_cycle(ReadSection::Bootloader).unwrap();
+
+    assert_eq!(bootloader, flash[0xf000..]);
+}
+
+#[test]
+fn test_read_range_cycle() {
+    let (device, _transport) = simulated_device(vec![0xaa; 65536]);
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+    device.write_cycle(&mut firmware).unwrap();
+
+    // spans the end of the first and the start of the second page
+    let range = device.read_range_cycle(0x07fe, 4).unwrap();
+    assert_eq!(range, firmware[0x07fe..0x0802]);
+
+    let reset_vector = device.read_range_cycle(0, 3).unwrap();
+    assert_eq!(reset_vector, [0x02, 0x00, 0x66]);
+}
+
+#[test]
+fn test_read_range_cycle_out_of_range() {
+    let (device, _transport) = simulated_device(vec![0xaa; 65536]);
+
+    let result = device.read_range_cycle(0xff00, 0x200);
+
+    assert!(matches!(
+        result,
+        Err(ISPError::AddressOutOfRange {
+            start: 0xff00,
+            end: 0x10100,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_retry_backoff() {
+    assert_eq!(retry_backoff(0), time::Duration::from_millis(100));
+    assert_eq!(retry_backoff(3), time::Duration::from_millis(800));
+    assert_eq!(retry_backoff(6), MAX_RETRY_BACKOFF);
+    assert_eq!(retry_backoff(7), MAX_RETRY_BACKOFF);
+    assert_eq!(retry_backoff(40), MAX_RETRY_BACKOFF);
+    assert_eq!(retry_backoff(usize::MAX), MAX_RETRY_BACKOFF);
+}
+
+#[test]
+fn test_read_range_cycle_overflow() {
+    let (device, _transport) = simulated_device(vec![0xaa; 65536]);
+
+    let result = device.read_range_cycle(usize::MAX - 1, 0x10);
+
+    assert!(matches!(
+        result,
+        Err(ISPError::AddressOutOfRange {
+            start,
+            end: usize::MAX,
+            size: 0x10000,
+        }) if start == usize::MAX - 1
+    ));
+}
+
+#[test]
+fn test_vote() {
+    let passes = vec![vec![1, 2, 3], vec![1, 2, 4], vec![1, 5, 3]];
+    assert_eq!(
+        vote(&passes, 0x800).unwrap(),
+        (vec![1, 2, 3], vec![0x801, 0x802])
+    );
+
+    let passes = vec![vec![1, 2, 3], vec![1, 2, 4]];
+    assert!(matches!(
+        vote(&passes, 0x800),
+        Err(ISPError::UnstableRead { addr: 0x802 })
+    ));
+}
+
+/// Flips a byte in the first page received, like a glitch on the bus
+#[cfg(test)]
+struct GlitchOnce(SimulatedTransport, std::cell::Cell<bool>);
+
+#[cfg(test)]
+impl ISPTransport for GlitchOnce {
+    fn send_feature_report(&self, data: &[u8]) -> Result<(), HidError> {
+        self.0.send_feature_report(data)
+    }
+
+    fn get_feature_report(&self, buf: &mut [u8]) -> Result<usize, HidError> {
+        let size = self.0.get_feature_report(buf)?;
+        if !self.1.replace(true) {
+            buf[0x12] ^= 0xff;
+        }
+        Ok(size)
+    }
+}
+
+#[test]
+fn test_read_cycle_passes() {
+    let (device, transport) = simulated_device(vec![0xaa; 65536]);
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+    device.write_cycle(&mut firmware).unwrap();
+
+    let device = device
+        .map_transport(|_| Box::new(GlitchOnce(transport.clone(), Default::default())))
+        .with_read_passes(2);
+    let read_back = device.read_cycle(ReadSection::Firmware).unwrap();
+
+    assert_eq!(read_back, test_payload(DEVICE_BASE_SH68F90));
+}
+
+#[test]
+fn test_blank_check() {
+    let (device, transport) = simulated_device(vec![0xaa; 65536]);
+    device.erase().unwrap();
+
+    assert!(device.blank_check().is_ok());
+    // the firmware was not enabled, the LJMP slot is still empty
+    assert_eq!(transport.flash()[0xeffb], ERASED_BYTE);
+}
+
+#[test]
+fn test_blank_check_not_blank() {
+    let (device, _transport) = simulated_device(vec![0xaa; 65536]);
+
+    let result = device.blank_check();
+
+    assert!(matches!(
+        result,
+        Err(ISPError::NotBlank {
+            addr: 0x0003,
+            value: 0xaa
+        })
+    ));
+}
+
+/// Acknowledges erase commands without passing them on, like a device failing silently
+#[cfg(test)]
+struct IgnoreErase(SimulatedTransport);
+
+#[cfg(test)]
+impl ISPTransport for IgnoreErase {
+    fn send_feature_report(&self, data: &[u8]) -> Result<(), HidError> {
+        match data {
+            [REPORT_ID_CMD, CMD_ERASE, ..] => Ok(()),
+            _ => self.0.send_feature_report(data),
+        }
+    }
+
+    fn get_feature_report(&self, buf: &mut [u8]) -> Result<usize, HidError> {
+        self.0.get_feature_report(buf)
+    }
+}
+
+#[test]
+fn test_write_cycle_verify_erase() {
+    let (device, transport) = simulated_device(vec![0xaa; 65536]);
+    let device = device.with_erase_verification(true);
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+
+    device.write_cycle(&mut firmware).unwrap();
+
+    assert_eq!(transport.flash()[3..0xeffb], firmware[3..0xeffb]);
+}
+
+#[test]
+fn test_write_cycle_verify_erase_incomplete() {
+    let (device, transport) = simulated_device(vec![0xaa; 65536]);
+    let device = device
+        .map_transport(|_| Box::new(IgnoreErase(transport.clone())))
+        .with_erase_verification(true);
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+
+    let result = device.write_cycle(&mut firmware);
+
+    assert!(matches!(
+        result,
+        Err(ISPError::NotBlank { addr: 0x0003, .. })
+    ));
+    assert_eq!(transport.flash(), vec![0xaa; 65536]);
+}
+
+#[test]
+fn test_write_cycle_dropped_report() {
+    // erase, init_write and 16 pages go through before page 17 fails
+    let (device, transport) = simulated_device_with_faults(vec![0xaa; 65536], &["drop@19"]);
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+
+    let result = device.write_cycle(&mut firmware);
+
+    assert!(matches!(result, Err(ISPError::HidError(_))));
+    let flash = transport.flash();
+    assert_eq!(flash[0x7800..0x8000], firmware[0x7800..0x8000]);
+    assert!(flash[0x8000..0xeffb].iter().all(|b| *b == 0x00));
+}
+
+#[test]
+fn test_write_cycle_dropped_report_retry() {
+    let (device, transport) = simulated_device_with_faults(vec![0xaa; 65536], &["drop@19"]);
+    let device = device.with_page_retries(1);
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+
+    device.write_cycle(&mut firmware).unwrap();
+
+    let flash = transport.flash();
+    assert_eq!(flash[3..0xeffb], firmware[3..0xeffb]);
+}
+
+#[test]
+fn test_write_cycle_retries_exhausted() {
+    // the retry re-initializes the write (20) before sending the page again (21)
+    let (device, _transport) =
+        simulated_device_with_faults(vec![0xaa; 65536], &["drop@19", "drop@21"]);
+    let device = device.with_page_retries(1);
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+
+    let result = device.write_cycle(&mut firmware);
+
+    assert!(matches!(result, Err(ISPError::HidError(_))));
+}
+
+#[test]
+fn test_read_cycle_corrupted_marker_retry() {
+    let flash: Vec<u8> = (0..65536).map(|i| (i % 251) as u8).collect();
+    let (device, _transport) = simulated_device(flash.clone());
+    let expected = device.read_cycle(ReadSection::Firmware).unwrap();
+
+    let (device, _transport) = simulated_device_with_faults(flash, &["corrupt@3"]);
+    let device = device.with_page_retries(1);
+    let read_back = device.read_cycle(ReadSection::Firmware).unwrap();
+
+    assert_eq!(read_back, expected);
+}
+
+#[test]
+fn test_write_cycle_journal() {
+    let path = std::env::temp_dir().join(format!(
+        "sinowealth-kb-tool-{}-isp-device.journal",
+        std::process::id()
+    ));
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+
+    let (device, _transport) = simulated_device_with_faults(vec![0xaa; 65536], &["drop@19"]);
+    let journal = Journal::create(&path, &firmware, device.device_spec).unwrap();
+    let device = device.with_journal(journal);
+    assert!(device.write_cycle(&mut firmware.clone()).is_err());
+    assert_eq!(Journal::open(&path).unwrap().unwrap().last_page, Some(15));
+
+    let (device, _transport) = simulated_device(vec![0xaa; 65536]);
+    let journal = Journal::open(&path).unwrap().unwrap();
+    let device = device.with_journal(journal);
+    device.write_cycle(&mut firmware).unwrap();
+    assert!(Journal::open(&path).unwrap().is_none());
+}
+
+#[test]
+fn test_write_cycle_journal_kept_until_enabled() {
+    let path = std::env::temp_dir().join(format!(
+        "sinowealth-kb-tool-{}-isp-device-enable.journal",
+        std::process::id()
+    ));
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+
+    // erase, init_write, 30 pages, init_read and 30 pages go through before enable_firmware
+    let (device, _transport) = simulated_device_with_faults(vec![0xaa; 65536], &["disconnect@64"]);
+    let journal = Journal::create(&path, &firmware, device.device_spec).unwrap();
+    let device = device.with_journal(journal);
+
+    assert!(matches!(
+        device.write_cycle(&mut firmware),
+        Err(ISPError::HidError(_))
+    ));
+    assert!(Journal::open(&path).unwrap().is_some());
+    Journal::open(&path).unwrap().unwrap().complete().unwrap();
+}
+
+#[test]
+fn test_write_cycle_disconnect_after_erase() {
+    let (device, transport) = simulated_device_with_faults(vec![0xaa; 65536], &["disconnect@2"]);
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+
+    let result = device.write_cycle(&mut firmware);
+
+    assert!(matches!(result, Err(ISPError::HidError(_))));
+    assert_eq!(transport.flash()[0..3], [0x02, 0xf0, 0x00]);
+}
+
+#[test]
+fn test_write_cycle_corrupted_page() {
+    let (device, _transport) = simulated_device_with_faults(vec![0xaa; 65536], &["corrupt@4"]);
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+
+    let result = device.write_cycle(&mut firmware);
+
+    assert!(matches!(
+        result,
+        Err(ISPError::VerificationError(
+            VerificationError::ByteMismatch { addr: 0x0fff, .. }
+        ))
+    ));
+}
+
+#[test]
+fn test_read_cycle_corrupted_marker() {
+    // enable_firmware and init_read precede the first page
+    let (device, _transport) = simulated_device_with_faults(vec![0x00; 65536], &["corrupt@3"]);
+
+    let result = device.read_cycle(ReadSection::Firmware);
+
+    assert!(matches!(result, Err(ISPError::ReadWriteMismatch)));
+}
@@ -0,0 +1,221 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::device_spec::DeviceSpec;
+
+#[cfg(test)]
+use crate::device_spec::{DEVICE_BASE_SH68F881, DEVICE_BASE_SH68F90};
+
+#[derive(Debug, Error)]
+pub enum JournalError {
+    #[error(transparent)]
+    IOError(#[from] io::Error),
+    #[error("No interrupted write found, journal {0} does not exist")]
+    NotFound(String),
+    #[error("Invalid write journal {path}: {message}")]
+    Invalid { path: String, message: String },
+    #[error("Write journal {0} was created for a different device")]
+    DeviceMismatch(String),
+    #[error("Image stored with write journal {0} does not match its hash")]
+    ImageMismatch(String),
+}
+
+/// On-disk record of a write in progress, so an interrupted write can be resumed.
+///
+/// The journal is kept next to a copy of the image being written and is replaced atomically
+/// every time a page is confirmed. It is removed once the write has been verified and the
+/// firmware enabled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Journal {
+    path: PathBuf,
+    pub image_md5: String,
+    pub device: String,
+    /// Index of the last page confirmed by the device
+    pub last_page: Option<usize>,
+}
+
+/// Identifies a device by everything that affects how an image is written to it
+fn device_key(device_spec: DeviceSpec) -> String {
+    format!(
+        "{:04x}:{:04x} firmware_size={} bootloader_size={} page_size={} isp_iface_num={} isp_report_id={}",
+        device_spec.vendor_id,
+        device_spec.product_id,
+        device_spec.platform.firmware_size,
+        device_spec.platform.bootloader_size,
+        device_spec.platform.page_size,
+        device_spec.isp_iface_num,
+        device_spec.isp_report_id
+    )
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn image_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bin")
+}
+
+impl Journal {
+    /// Default journal location for a device, in the temporary directory
+    pub fn default_path(device_spec: DeviceSpec) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "sinowealth-kb-tool-{:04x}-{:04x}.journal",
+            device_spec.vendor_id, device_spec.product_id
+        ))
+    }
+
+    /// Starts a journal for writing `image` and stores a copy of the image with it
+    pub fn create(
+        path: &Path,
+        image: &[u8],
+        device_spec: DeviceSpec,
+    ) -> Result<Self, JournalError> {
+        write_atomically(&image_path(path), image)?;
+        let journal = Self {
+            path: path.to_path_buf(),
+            image_md5: format!("{:x}", md5::compute(image)),
+            device: device_key(device_spec),
+            last_page: None,
+        };
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// Loads a journal, returns `None` if there is no write to resume
+    pub fn open(path: &Path) -> Result<Option<Self>, JournalError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let invalid = |message: &str| JournalError::Invalid {
+            path: path.display().to_string(),
+            message: message.to_string(),
+        };
+
+        let mut image_md5 = None;
+        let mut device = None;
+        let mut last_page = None;
+        for line in contents.lines() {
+            match line.split_once(": ") {
+                Some(("image_md5", value)) => image_md5 = Some(value.to_string()),
+                Some(("device", value)) => device = Some(value.to_string()),
+                Some(("last_page", "none")) => last_page = None,
+                Some(("last_page", value)) => {
+                    last_page = Some(value.parse().map_err(|_| invalid("invalid last page"))?)
+                }
+                _ => return Err(invalid("unexpected line")),
+            }
+        }
+
+        Ok(Some(Self {
+            path: path.to_path_buf(),
+            image_md5: image_md5.ok_or_else(|| invalid("missing image hash"))?,
+            device: device.ok_or_else(|| invalid("missing device"))?,
+            last_page,
+        }))
+    }
+
+    /// Returns the stored image after checking it belongs to this journal and device
+    pub fn image(&self, device_spec: DeviceSpec) -> Result<Vec<u8>, JournalError> {
+        let path = self.path.display().to_string();
+        if self.device != device_key(device_spec) {
+            return Err(JournalError::DeviceMismatch(path));
+        }
+        let image = fs::read(image_path(&self.path))?;
+        if format!("{:x}", md5::compute(&image)) != self.image_md5 {
+            return Err(JournalError::ImageMismatch(path));
+        }
+        Ok(image)
+    }
+
+    pub fn record_page(&mut self, page: usize) -> Result<(), JournalError> {
+        self.last_page = Some(page);
+        self.save()
+    }
+
+    /// Removes the journal and the stored image after the write completed
+    pub fn complete(&self) -> Result<(), JournalError> {
+        fs::remove_file(image_path(&self.path))?;
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+
+    fn save(&self) -> Result<(), JournalError> {
+        let last_page = self
+            .last_page
+            .map(|page| page.to_string())
+            .unwrap_or("none".to_string());
+        let contents = format!(
+            "image_md5: {}\ndevice: {}\nlast_page: {}\n",
+            self.image_md5, self.device, last_page
+        );
+        write_atomically(&self.path, contents.as_bytes())
+    }
+}
+
+/// Writes to a temporary file first, so a crash never leaves a partially written file behind
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), JournalError> {
+    let tmp_path = with_suffix(path, ".tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_journal_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "sinowealth-kb-tool-{}-{}.journal",
+        std::process::id(),
+        name
+    ))
+}
+
+#[test]
+fn test_journal_roundtrip() {
+    let path = test_journal_path("roundtrip");
+    let image = vec![0x02, 0x00, 0x66, 0x12];
+
+    let mut journal = Journal::create(&path, &image, DEVICE_BASE_SH68F90).unwrap();
+    journal.record_page(12).unwrap();
+
+    let reopened = Journal::open(&path).unwrap().unwrap();
+    assert_eq!(reopened, journal);
+    assert_eq!(reopened.last_page, Some(12));
+    assert_eq!(reopened.image(DEVICE_BASE_SH68F90).unwrap(), image);
+
+    journal.complete().unwrap();
+    assert!(Journal::open(&path).unwrap().is_none());
+}
+
+#[test]
+fn test_journal_device_mismatch() {
+    let path = test_journal_path("device_mismatch");
+    let journal = Journal::create(&path, &[0x02], DEVICE_BASE_SH68F90).unwrap();
+
+    assert!(matches!(
+        journal.image(DEVICE_BASE_SH68F881),
+        Err(JournalError::DeviceMismatch(_))
+    ));
+    journal.complete().unwrap();
+}
+
+#[test]
+fn test_journal_image_mismatch() {
+    let path = test_journal_path("image_mismatch");
+    let journal = Journal::create(&path, &[0x02], DEVICE_BASE_SH68F90).unwrap();
+    fs::write(image_path(&path), [0x03]).unwrap();
+
+    assert!(matches!(
+        journal.image(DEVICE_BASE_SH68F90),
+        Err(JournalError::ImageMismatch(_))
+    ));
+    journal.complete().unwrap();
+}
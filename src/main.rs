@@ -1,32 +1,53 @@
 use std::{
     env, fs,
     io::{self, Read},
-    path::Path,
+    path::{Path, PathBuf},
     process::ExitCode,
+    rc::Rc,
     str::FromStr,
+    time::SystemTime,
 };
 
-use clap::{arg, value_parser, ArgMatches, Command};
+use clap::{
+    arg, error::ErrorKind, parser::ValueSource, value_parser, ArgAction, ArgGroup, ArgMatches,
+    Command,
+};
 use clap_num::maybe_hex;
-use device_selector::{DeviceSelector, DeviceSelectorError};
+use device_selector::{DeviceSelector, DeviceSelectorError, ISP_MODE_REPORT};
 use dialoguer::Confirm;
+use fault_injector::{Fault, FaultInjector};
 use hid_tree::TreeDisplay;
+use journal::{Journal, JournalError};
 use log::error;
+use pcap::{extract_feature_reports, rebuild_firmware, PcapError};
+use plan::Plan;
 use platform_spec::PlatformSpec;
 use simple_logger::SimpleLogger;
 use thiserror::Error;
+use trace::{RecordingTransport, ReplayTransport, TraceError, TraceRecorder};
+use transport::SimulatedTransport;
+use updater::{find_firmware_candidates, UpdaterError};
 
 mod device_selector;
 mod device_spec;
+mod fault_injector;
 mod hid_tree;
 mod ihex;
 mod isp_device;
+mod journal;
+mod pcap;
+mod plan;
 mod platform_spec;
+mod simulator;
+mod trace;
+mod transport;
+mod updater;
 mod util;
 
 pub use crate::{device_spec::*, ihex::*, isp_device::*, util::*};
 
 const DEFAULT_RETRY_COUNT: &str = "5";
+const DEFAULT_PAGE_RETRY_COUNT: &str = "3";
 
 #[derive(Debug, Error)]
 pub enum CLIError {
@@ -39,7 +60,21 @@ pub enum CLIError {
     #[error(transparent)]
     PayloadConversionError(#[from] PayloadConversionError),
     #[error(transparent)]
+    PayloadValidationError(#[from] PayloadValidationError),
+    #[error(transparent)]
     DeviceSelectorError(#[from] DeviceSelectorError),
+    #[error(transparent)]
+    TraceError(#[from] TraceError),
+    #[error(transparent)]
+    PcapError(#[from] PcapError),
+    #[error(transparent)]
+    UpdaterError(#[from] UpdaterError),
+    #[error(transparent)]
+    VerificationError(#[from] VerificationError),
+    #[error(transparent)]
+    JournalError(#[from] JournalError),
+    #[error("Payload failed validation, use --skip_validation to write it anyway")]
+    InvalidPayload,
 }
 
 #[derive(Clone, Copy)]
@@ -105,32 +140,136 @@ fn cli() -> Command {
                         .value_parser(ReadSection::available_sections())
                         .default_value(ReadSection::Firmware.to_str()),
                 )
+                .arg(
+                    arg!(--passes <N> "read every page N times and majority-vote differing pages")
+                        .value_parser(value_parser!(u8).range(1..))
+                        .default_value("1"),
+                )
+                .arg(
+                    arg!(--start <ADDR> "address to start reading from, instead of a section")
+                        .value_parser(maybe_hex::<usize>)
+                        .conflicts_with("section"),
+                )
+                .arg(
+                    arg!(--length <LEN> "number of bytes to read, defaults to the end of flash")
+                        .value_parser(maybe_hex::<usize>)
+                        .conflicts_with("section"),
+                )
+                .arg(
+                    arg!(--layout <LAYOUT> "isp as returned by the bootloader, or the physical flash contents as read over JTAG")
+                        .value_parser(["isp", "physical"])
+                        .default_value("isp"),
+                )
                 .arg(
                     arg!(-r --retry <NUM> "number of attempts trying to find device")
                         .value_parser(value_parser!(usize))
                         .default_value(DEFAULT_RETRY_COUNT),
                 )
-                .device_args(),
+                .device_args()
+                .transport_args(),
         )
         .subcommand(
             Command::new("write")
                 .about("Write a file into flash.")
-                .arg(arg!(input_file: <INPUT_FILE> "payload to write into flash"))
+                .arg(
+                    arg!(input_file: [INPUT_FILE] "payload to write into flash")
+                        .required_unless_present("resume")
+                        .conflicts_with("resume"),
+                )
                 .arg(arg!(-f --force "ignore firmware size check"))
+                .arg(arg!(--skip_validation "write payloads that fail validation, e.g. without an LJMP at 0x0000"))
+                .arg(arg!(--resume "restart an interrupted write with the image stored in its journal"))
+                .arg(arg!(--journal <FILE> "location of the write journal"))
+                .arg(arg!(--no_backup "do not back up the current firmware before writing"))
+                .arg(arg!(--backup_dir <DIR> "directory to store the firmware backup in"))
+                .arg(
+                    arg!(--auto_rollback "write the backup back without asking if the write fails")
+                        .conflicts_with("no_backup"),
+                )
+                .arg(arg!(--verify_erase "check that flash is blank after erasing, before writing"))
+                .arg(
+                    arg!(--if_changed "skip the write if the device already contains the payload")
+                        .conflicts_with("resume"),
+                )
+                .arg(
+                    arg!(--preserve <RANGE> "keep the device contents of a flash range, e.g. 0xe000-0xefff")
+                        .value_parser(value_parser!(AddressRange))
+                        .action(ArgAction::Append)
+                        .conflicts_with("resume"),
+                )
                 .arg(arg!(--format <FORMAT>).value_parser(Format::available_formats()))
                 .arg(
                     arg!(-r --retry <NUM> "number of attempts trying to find device")
                         .value_parser(value_parser!(usize))
                         .default_value(DEFAULT_RETRY_COUNT),
                 )
-                .device_args(),
+                .device_args()
+                .transport_args(),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Compare flash contents with a file.")
+                .arg(arg!(input_file: <INPUT_FILE> "payload to compare flash contents with"))
+                .arg(arg!(--format <FORMAT>).value_parser(Format::available_formats()))
+                .arg(
+                    arg!(-r --retry <NUM> "number of attempts trying to find device")
+                        .value_parser(value_parser!(usize))
+                        .default_value(DEFAULT_RETRY_COUNT),
+                )
+                .device_args()
+                .transport_args(),
+        )
+        .subcommand(
+            Command::new("blank-check")
+                .about("Check that the firmware section is erased.")
+                .arg(
+                    arg!(-r --retry <NUM> "number of attempts trying to find device")
+                        .value_parser(value_parser!(usize))
+                        .default_value(DEFAULT_RETRY_COUNT),
+                )
+                .device_args()
+                .transport_args(),
+        )
+        .subcommand(
+            Command::new("erase")
+                .about("Erase the firmware, leaving the device in ISP mode.")
+                .arg(arg!(-f --force "skip confirmation"))
+                .arg(
+                    arg!(-r --retry <NUM> "number of attempts trying to find device")
+                        .value_parser(value_parser!(usize))
+                        .default_value(DEFAULT_RETRY_COUNT),
+                )
+                .device_args()
+                .transport_args(),
+        )
+        .subcommand(
+            Command::new("enable")
+                .about("Enable the firmware, so the bootloader starts it on reset.")
+                .arg(
+                    arg!(-r --retry <NUM> "number of attempts trying to find device")
+                        .value_parser(value_parser!(usize))
+                        .default_value(DEFAULT_RETRY_COUNT),
+                )
+                .device_args()
+                .transport_args(),
+        )
+        .subcommand(
+            Command::new("reboot")
+                .about("Reboot a device in ISP mode into its firmware.")
+                .arg(
+                    arg!(-r --retry <NUM> "number of attempts trying to find device")
+                        .value_parser(value_parser!(usize))
+                        .default_value(DEFAULT_RETRY_COUNT),
+                )
+                .device_args()
+                .transport_args(),
         )
         .subcommand(
             Command::new("convert")
                 .about("Convert payload from ISP to JTAG and vice versa.")
                 .arg(
                     arg!(--direction <DIRECTION> "direction of conversion")
-                        .value_parser(["to_jtag", "to_isp"])
+                        .value_parser(["to_jtag", "to_isp", "auto"])
                         .required(true),
                 )
                 .arg(arg!(--input_format <FORMAT>).value_parser(Format::available_formats()))
@@ -139,6 +278,47 @@ fn cli() -> Command {
                 .arg(arg!(output_file: <OUTPUT_FILE> "file to write results to"))
                 .device_args(), // TODO: not all of these args are needed and should be removed
         )
+        .subcommand(
+            Command::new("split")
+                .about("Split a full flash image into firmware and bootloader.")
+                .arg(arg!(--input_format <FORMAT>).value_parser(Format::available_formats()))
+                .arg(arg!(--output_format <FORMAT>).value_parser(Format::available_formats()))
+                .arg(arg!(input_file: <INPUT_FILE> "full flash image, e.g. a JTAG dump"))
+                .arg(arg!(firmware_file: <FIRMWARE_FILE> "file to write the firmware section to"))
+                .arg(arg!(bootloader_file: <BOOTLOADER_FILE> "file to write the bootloader section to"))
+                .device_args(),
+        )
+        .subcommand(
+            Command::new("merge")
+                .about("Merge firmware and bootloader into a full flash image for JTAG.")
+                .arg(arg!(--input_format <FORMAT>).value_parser(Format::available_formats()))
+                .arg(arg!(--output_format <FORMAT>).value_parser(Format::available_formats()))
+                .arg(arg!(firmware_file: <FIRMWARE_FILE> "firmware in the ISP or JTAG layout"))
+                .arg(arg!(bootloader_file: <BOOTLOADER_FILE> "bootloader, e.g. from read -s bootloader"))
+                .arg(arg!(output_file: <OUTPUT_FILE> "file to write the full flash image to"))
+                .device_args(),
+        )
+        .subcommand(
+            Command::new("extract-pcap")
+                .about(
+                    "Extract firmware written by a vendor updater from a usbmon/USBPcap capture.",
+                )
+                .arg(arg!(input_file: <INPUT_FILE> "pcap or pcapng capture of the update"))
+                .arg(arg!(output_file: <OUTPUT_FILE> "file to write the extracted firmware to"))
+                .arg(arg!(--format <FORMAT>).value_parser(Format::available_formats()))
+                .device_args(),
+        )
+        .subcommand(
+            Command::new("extract-updater")
+                .about("Find firmware images embedded in a vendor updater executable.")
+                .arg(arg!(input_file: <INPUT_FILE> "updater executable"))
+                .arg(arg!(--output_dir <DIR> "directory to write the found images to"))
+                .arg(
+                    arg!(--format <FORMAT>)
+                        .value_parser(Format::available_formats())
+                        .default_value(Format::Binary.to_str()),
+                ),
+        )
 }
 
 fn err_main() -> Result<(), CLIError> {
@@ -173,16 +353,83 @@ fn err_main() -> Result<(), CLIError> {
 
             let device_spec = get_device_spec_from_matches(sub_matches);
 
-            let mut ds = DeviceSelector::new().map_err(CLIError::DeviceSelectorError)?;
-            let device = ds
-                .try_fetch_isp_device(device_spec, retry_count)
+            let start = sub_matches.get_one::<usize>("start").copied();
+            let length = sub_matches.get_one::<usize>("length").copied();
+
+            let passes = sub_matches.get_one::<u8>("passes").copied().unwrap();
+
+            // the physical layout is rebuilt from all of flash
+            let physical = sub_matches
+                .get_one::<String>("layout")
+                .is_some_and(|layout| layout == "physical");
+
+            if physical {
+                let explicit = ["section", "start", "length"]
+                    .into_iter()
+                    .find(|arg| sub_matches.value_source(arg) == Some(ValueSource::CommandLine));
+                if let Some(arg) = explicit {
+                    cli()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            format!(
+                                "the argument '--layout physical' cannot be used with '--{}'",
+                                arg
+                            ),
+                        )
+                        .exit();
+                }
+            }
+
+            let (start, length) = if physical {
+                ReadSection::Full.bounds(device_spec)
+            } else if start.is_some() || length.is_some() {
+                let start = start.unwrap_or(0);
+                let length = length.unwrap_or(device_spec.total_flash_size().saturating_sub(start));
+                (start, length)
+            } else {
+                section.bounds(device_spec)
+            };
+
+            if sub_matches.get_flag("dry_run") {
+                check_flash_range(device_spec, start, length)?;
+                let mut plan = plan_isp_device(sub_matches, device_spec, retry_count);
+                plan.read_range(start, length, passes as usize);
+                plan.maybe_reboot();
+                if physical {
+                    let firmware_size = device_spec.platform.firmware_size;
+                    plan.step(format!(
+                        "Rebuild the physical layout: move the reset vector address to the LJMP at {:#06x} and point 0x0000 to the bootloader at {:#06x}",
+                        firmware_size - 5,
+                        firmware_size
+                    ));
+                }
+                plan.step(format!(
+                    "Save {} bytes to {} ({})",
+                    length,
+                    output_file,
+                    format.to_str()
+                ));
+                eprint!("{}", plan);
+                return Ok(());
+            }
+
+            let device = fetch_isp_device(sub_matches, device_spec, retry_count)?
+                .with_read_passes(passes as usize);
+            let mut firmware = device
+                .read_range_cycle(start, length)
                 .map_err(CLIError::from)?;
-            let firmware = device.read_cycle(section).map_err(CLIError::from)?;
+            device.finish()?;
+
+            if physical {
+                let firmware_size = device_spec.platform.firmware_size;
+                convert_to_jtag_payload(&mut firmware[..firmware_size], device_spec)?;
+                eprintln!("Rebuilt the physical flash layout");
+            }
 
             let digest = md5::compute(&firmware);
             eprintln!("MD5: {:x}", digest);
 
-            write_with_format(output_file, &firmware, format)?;
+            write_with_format(output_file, &firmware, format, start)?;
 
             eprintln!(
                 "Successfully read {} bytes - {}",
@@ -191,11 +438,6 @@ fn err_main() -> Result<(), CLIError> {
             );
         }
         Some(("write", sub_matches)) => {
-            let input_file = sub_matches
-                .get_one::<String>("input_file")
-                .map(|s| s.as_str())
-                .unwrap();
-
             let retry_count = sub_matches
                 .get_one::<usize>("retry")
                 .map(|s| s.to_owned())
@@ -203,41 +445,429 @@ fn err_main() -> Result<(), CLIError> {
 
             let force = sub_matches.get_flag("force");
 
-            let format = get_format_from_matches(sub_matches, input_file, "format");
+            let resume = sub_matches.get_flag("resume");
+
+            let dry_run = sub_matches.get_flag("dry_run");
+
+            let skip_validation = sub_matches.get_flag("skip_validation");
 
             let device_spec = get_device_spec_from_matches(sub_matches);
 
-            let mut firmware = read_with_format(input_file, format)?;
+            let journal_path = sub_matches
+                .get_one::<String>("journal")
+                .map(PathBuf::from)
+                .or_else(|| {
+                    // a simulated device is identified by its flash file rather than its ids
+                    sub_matches
+                        .get_one::<String>("simulate")
+                        .map(|flash_file| PathBuf::from(format!("{}.journal", flash_file)))
+                })
+                .unwrap_or_else(|| Journal::default_path(device_spec));
 
-            if firmware.len() < device_spec.platform.firmware_size {
+            let (mut firmware, journal) = if resume {
+                let journal = Journal::open(&journal_path)?
+                    .ok_or_else(|| JournalError::NotFound(journal_path.display().to_string()))?;
+                let firmware = journal.image(device_spec)?;
+                match journal.last_page {
+                    Some(page) => eprintln!(
+                        "Resuming write of {} interrupted after page {}, restarting from erase",
+                        journal.image_md5, page
+                    ),
+                    None => eprintln!(
+                        "Resuming write of {} interrupted before the first page, restarting from erase",
+                        journal.image_md5
+                    ),
+                }
+                (firmware, Some(journal))
+            } else {
+                let input_file = sub_matches
+                    .get_one::<String>("input_file")
+                    .map(|s| s.as_str())
+                    .unwrap();
+
+                let format = get_format_from_matches(sub_matches, input_file, "format");
+
+                let mut firmware = read_with_format(input_file, format, 0)?;
+
+                if firmware.len() < device_spec.platform.firmware_size {
+                    eprintln!(
+                        "Warning: firmware size is less than expected ({}). It will be resized to {} and filled with 0",
+                        firmware.len(),
+                        device_spec.platform.firmware_size
+                    );
+                    if !force && !dry_run {
+                        eprintln!("Use --force skip confirmation");
+                        let confirmation = Confirm::new()
+                            .with_prompt("Are you sure you want to continue?")
+                            .default(false)
+                            .interact()
+                            .unwrap();
+
+                        if !confirmation {
+                            return Ok(());
+                        }
+                    }
+                    firmware.resize(device_spec.platform.firmware_size, 0);
+                }
+
+                prepare_isp_payload(&mut firmware, device_spec)?;
+
+                let issues = validate_payload(&firmware, device_spec);
+                for issue in &issues {
+                    if skip_validation {
+                        eprintln!("Warning: {}", issue);
+                    } else {
+                        eprintln!("Error: {}", issue);
+                    }
+                }
+                if !issues.is_empty() && !skip_validation {
+                    return Err(CLIError::InvalidPayload);
+                }
+                if firmware.len() > device_spec.platform.firmware_size {
+                    eprintln!(
+                        "Warning: dropping {} bytes past the firmware section",
+                        firmware.len() - device_spec.platform.firmware_size
+                    );
+                    firmware.truncate(device_spec.platform.firmware_size);
+                }
+
+                if Journal::open(&journal_path)?.is_some() {
+                    eprintln!(
+                        "Warning: replacing the journal of an interrupted write ({}), use --resume to restart that write instead",
+                        journal_path.display()
+                    );
+                }
+                (firmware, None)
+            };
+
+            let preserve: Vec<AddressRange> = sub_matches
+                .get_many::<AddressRange>("preserve")
+                .map(|ranges| ranges.copied().collect())
+                .unwrap_or_default();
+            let firmware_size = device_spec.platform.firmware_size;
+            if let Some(range) = preserve.iter().find(|range| range.end >= firmware_size) {
+                return Err(CLIError::from(ISPError::AddressOutOfRange {
+                    start: range.start,
+                    end: range.end + 1,
+                    size: firmware_size,
+                }));
+            }
+
+            let verify_erase = sub_matches.get_flag("verify_erase");
+
+            let if_changed = sub_matches.get_flag("if_changed");
+
+            let no_backup = sub_matches.get_flag("no_backup");
+            let auto_rollback = sub_matches.get_flag("auto_rollback");
+
+            let backup_dir = sub_matches
+                .get_one::<String>("backup_dir")
+                .map(PathBuf::from)
+                .or_else(|| {
+                    // keep backups of a simulated device next to its flash file
+                    sub_matches
+                        .get_one::<String>("simulate")
+                        .and_then(|flash_file| {
+                            Path::new(flash_file).parent().map(Path::to_path_buf)
+                        })
+                })
+                .unwrap_or_default();
+
+            // the current firmware is read for the backup and the comparison, an interrupted write
+            // left nothing worth backing up
+            let read_current = if_changed || !(no_backup || resume);
+
+            if dry_run {
                 eprintln!(
-                    "Warning: firmware size is less than expected ({}). It will be resized to {} and filled with 0",
+                    "Payload: {} bytes (MD5: {:x})",
                     firmware.len(),
-                    device_spec.platform.firmware_size
+                    md5::compute(&firmware)
                 );
-                if !force {
-                    eprintln!("Use --force skip confirmation");
-                    let confirmation = Confirm::new()
-                        .with_prompt("Are you sure you want to continue?")
-                        .default(false)
-                        .interact()
-                        .unwrap();
-
-                    if !confirmation {
-                        return Ok(());
+                let mut plan = plan_isp_device(sub_matches, device_spec, retry_count)
+                    .with_journal(&journal_path);
+                if read_current {
+                    plan.read_range(0, device_spec.platform.firmware_size, 1);
+                }
+                for range in &preserve {
+                    if !read_current {
+                        plan.read_range(range.start, range.to_range().len(), 1);
                     }
+                    let overlaps = firmware[range.to_range()].iter().any(|&b| b != 0);
+                    plan.step(format!(
+                        "Merge the device contents of {} into the payload{}",
+                        range,
+                        if overlaps {
+                            ", replacing data in the payload"
+                        } else {
+                            ""
+                        }
+                    ));
                 }
-                firmware.resize(device_spec.platform.firmware_size, 0);
+                if if_changed {
+                    plan.step("Compare the device contents with the payload, if they match skip the steps below and reboot");
+                }
+                if read_current && !no_backup {
+                    plan.step(format!(
+                        "Save a backup to {}",
+                        backup_path(&backup_dir, device_spec, "<TIMESTAMP>").display()
+                    ));
+                }
+                plan.write_cycle(&firmware, verify_erase);
+                if read_current && !no_backup {
+                    plan.step(if auto_rollback {
+                        "If the write fails, write the backup back"
+                    } else {
+                        "If the write fails, offer to write the backup back"
+                    });
+                }
+                eprint!("{}", plan);
+                return Ok(());
             }
 
-            let mut ds = DeviceSelector::new().map_err(CLIError::DeviceSelectorError)?;
-            let device = ds
-                .try_fetch_isp_device(device_spec, retry_count)
-                .map_err(CLIError::from)?;
-            device.write_cycle(&mut firmware).map_err(CLIError::from)?;
+            let device = fetch_isp_device(sub_matches, device_spec, retry_count)?
+                .with_erase_verification(verify_erase);
+
+            let current = if read_current {
+                eprintln!("Reading current firmware...");
+                Some(
+                    device
+                        .read_range(0, device_spec.platform.firmware_size)
+                        .map_err(CLIError::from)?,
+                )
+            } else {
+                None
+            };
+
+            for range in &preserve {
+                let contents = match &current {
+                    Some(current) => current[range.to_range()].to_vec(),
+                    None => device
+                        .read_range(range.start, range.to_range().len())
+                        .map_err(CLIError::from)?,
+                };
+                if merge_preserved(&mut firmware, range.to_range(), &contents) {
+                    eprintln!(
+                        "Warning: preserved range {} overlaps data in the payload, it will be replaced with the device contents",
+                        range
+                    );
+                }
+                eprintln!("Preserving {}", range);
+            }
+
+            if let Some(current) = current.as_ref().filter(|_| if_changed) {
+                // compare the payload the way write_cycle reads it back
+                let mut expected = firmware.clone();
+                clear_reset_vector_copy(&mut expected, device_spec);
+                if expected == *current {
+                    eprintln!(
+                        "Device already contains the payload (MD5: {:x}), skipping write",
+                        md5::compute(current)
+                    );
+                    if device_spec.reboot {
+                        device.reboot();
+                    }
+                    device.finish()?;
+                    return Ok(());
+                }
+                eprintln!("Device contents differ from the payload");
+            }
+
+            let backup = match current {
+                Some(current) if !no_backup => {
+                    save_backup(&backup_dir, device_spec, &current)?;
+                    Some(current)
+                }
+                _ => None,
+            };
+
+            // the journal stores the image as it is written, with preserved ranges merged in
+            let journal = match journal {
+                Some(journal) => journal,
+                None => Journal::create(&journal_path, &firmware, device_spec)?,
+            };
+            let device = device.with_journal(journal);
+
+            if let Err(err) = device.write_cycle(&mut firmware) {
+                if let Some(backup) = backup {
+                    eprintln!("Write failed: {}", err);
+                    rollback(device, device_spec, backup, &journal_path, auto_rollback)?;
+                }
+                return Err(CLIError::from(err));
+            }
+            device.finish()?;
+
+            // write_cycle verified the whole firmware section, preserved ranges included
+            for range in &preserve {
+                eprintln!("Verified preserved range {}", range);
+            }
 
             eprintln!("Successfully wrote {} bytes", firmware.len());
         }
+        Some(("verify", sub_matches)) => {
+            let input_file = sub_matches
+                .get_one::<String>("input_file")
+                .map(|s| s.as_str())
+                .unwrap();
+
+            let retry_count = sub_matches
+                .get_one::<usize>("retry")
+                .map(|s| s.to_owned())
+                .unwrap();
+
+            let format = get_format_from_matches(sub_matches, input_file, "format");
+
+            let device_spec = get_device_spec_from_matches(sub_matches);
+
+            let firmware_size = device_spec.platform.firmware_size;
+            let mut expected = read_with_format(input_file, format, 0)?;
+            if expected.len() < firmware_size {
+                eprintln!(
+                    "Warning: file size ({}) is less than the firmware size ({}). It will be resized and filled with 0",
+                    expected.len(),
+                    firmware_size
+                );
+                expected.resize(firmware_size, 0);
+            }
+            // compare against what write would flash for the same file
+            prepare_isp_payload(&mut expected, device_spec)?;
+            if expected.len() > firmware_size {
+                return Err(CLIError::from(PayloadValidationError::TooLarge {
+                    size: expected.len(),
+                    firmware_size,
+                }));
+            }
+            clear_reset_vector_copy(&mut expected, device_spec);
+
+            if sub_matches.get_flag("dry_run") {
+                let mut plan = plan_isp_device(sub_matches, device_spec, retry_count);
+                plan.read_range(0, device_spec.platform.firmware_size, 1);
+                plan.maybe_reboot();
+                plan.step(format!("Compare the firmware with {}", input_file));
+                eprint!("{}", plan);
+                return Ok(());
+            }
+
+            let device = fetch_isp_device(sub_matches, device_spec, retry_count)?;
+            let firmware = device
+                .read_cycle(ReadSection::Firmware)
+                .map_err(CLIError::from)?;
+            device.finish()?;
+
+            eprintln!("Verifying...");
+            if let Err(err) = verify(&expected, &firmware) {
+                for range in mismatch_ranges(&expected, &firmware) {
+                    eprintln!(
+                        "Mismatch @ {:#06x}-{:#06x} ({} bytes)",
+                        range.start,
+                        range.end - 1,
+                        range.len()
+                    );
+                }
+                return Err(CLIError::from(err));
+            }
+
+            eprintln!("Flash contents match {}", input_file);
+        }
+        Some(("blank-check", sub_matches)) => {
+            let retry_count = sub_matches
+                .get_one::<usize>("retry")
+                .map(|s| s.to_owned())
+                .unwrap();
+
+            let device_spec = get_device_spec_from_matches(sub_matches);
+
+            if sub_matches.get_flag("dry_run") {
+                let mut plan = plan_isp_device(sub_matches, device_spec, retry_count);
+                plan.read_pages(0..device_spec.platform.firmware_size, 1);
+                plan.step("Check that the firmware section is blank");
+                eprint!("{}", plan);
+                return Ok(());
+            }
+
+            let device = fetch_isp_device(sub_matches, device_spec, retry_count)?;
+            device.blank_check().map_err(CLIError::from)?;
+            device.finish()?;
+
+            eprintln!("Firmware section is blank");
+        }
+        Some(("erase", sub_matches)) => {
+            let retry_count = sub_matches
+                .get_one::<usize>("retry")
+                .map(|s| s.to_owned())
+                .unwrap();
+
+            let force = sub_matches.get_flag("force");
+
+            let device_spec = get_device_spec_from_matches(sub_matches);
+
+            if sub_matches.get_flag("dry_run") {
+                let mut plan = plan_isp_device(sub_matches, device_spec, retry_count);
+                plan.erase();
+                plan.step("Leave the device in ISP mode");
+                eprint!("{}", plan);
+                return Ok(());
+            }
+
+            if !force {
+                eprintln!("Warning: the firmware will be erased and the device will stay in ISP mode until new firmware is written");
+                eprintln!("Use --force skip confirmation");
+                let confirmation = Confirm::new()
+                    .with_prompt("Are you sure you want to continue?")
+                    .default(false)
+                    .interact()
+                    .unwrap();
+
+                if !confirmation {
+                    return Ok(());
+                }
+            }
+
+            let device = fetch_isp_device(sub_matches, device_spec, retry_count)?;
+            device.erase().map_err(CLIError::from)?;
+            device.finish()?;
+
+            eprintln!("Successfully erased firmware");
+        }
+        Some(("enable", sub_matches)) => {
+            let retry_count = sub_matches
+                .get_one::<usize>("retry")
+                .map(|s| s.to_owned())
+                .unwrap();
+
+            let device_spec = get_device_spec_from_matches(sub_matches);
+
+            if sub_matches.get_flag("dry_run") {
+                let mut plan = plan_isp_device(sub_matches, device_spec, retry_count);
+                plan.enable_firmware();
+                eprint!("{}", plan);
+                return Ok(());
+            }
+
+            let device = fetch_isp_device(sub_matches, device_spec, retry_count)?;
+            device.enable_firmware().map_err(CLIError::from)?;
+            device.finish()?;
+
+            eprintln!("Successfully enabled firmware");
+        }
+        Some(("reboot", sub_matches)) => {
+            let retry_count = sub_matches
+                .get_one::<usize>("retry")
+                .map(|s| s.to_owned())
+                .unwrap();
+
+            let device_spec = get_device_spec_from_matches(sub_matches);
+
+            if sub_matches.get_flag("dry_run") {
+                let mut plan = plan_isp_device(sub_matches, device_spec, retry_count);
+                plan.reboot();
+                eprint!("{}", plan);
+                return Ok(());
+            }
+
+            let device = fetch_isp_device(sub_matches, device_spec, retry_count)?;
+            device.reboot();
+            device.finish()?;
+        }
         Some(("list", sub_matches)) => {
             let vendor_id = sub_matches.get_one::<u16>("vendor_id");
             let product_id = sub_matches.get_one::<u16>("product_id");
@@ -286,7 +916,7 @@ fn err_main() -> Result<(), CLIError> {
 
             let device_spec = get_device_spec_from_matches(sub_matches);
 
-            let mut firmware = read_with_format(input_file, input_format)?;
+            let mut firmware = read_with_format(input_file, input_format, 0)?;
 
             if firmware.len() < device_spec.platform.firmware_size {
                 log::warn!(
@@ -297,6 +927,20 @@ fn err_main() -> Result<(), CLIError> {
                 firmware.resize(device_spec.platform.firmware_size, 0);
             }
 
+            let direction = match direction {
+                "auto" => {
+                    let layout = detect_layout(&firmware, device_spec)
+                        .ok_or(PayloadConversionError::UnknownLayoutError)?;
+                    let direction = match layout {
+                        PayloadLayout::ISP => "to_jtag",
+                        PayloadLayout::JTAG => "to_isp",
+                    };
+                    eprintln!("Detected {} layout, converting {}", layout, direction);
+                    direction
+                }
+                direction => direction,
+            };
+
             match direction {
                 "to_jtag" => {
                     convert_to_jtag_payload(&mut firmware, device_spec).map_err(CLIError::from)?;
@@ -321,7 +965,172 @@ fn err_main() -> Result<(), CLIError> {
                 _ => unreachable!(),
             }
 
-            write_with_format(output_file, &firmware, output_format)?;
+            write_with_format(output_file, &firmware, output_format, 0)?;
+        }
+        Some(("split", sub_matches)) => {
+            let input_file = sub_matches
+                .get_one::<String>("input_file")
+                .map(|s| s.as_str())
+                .unwrap();
+            let firmware_file = sub_matches
+                .get_one::<String>("firmware_file")
+                .map(|s| s.as_str())
+                .unwrap();
+            let bootloader_file = sub_matches
+                .get_one::<String>("bootloader_file")
+                .map(|s| s.as_str())
+                .unwrap();
+
+            let input_format = get_format_from_matches(sub_matches, input_file, "input_format");
+
+            let device_spec = get_device_spec_from_matches(sub_matches);
+
+            let input = read_with_format(input_file, input_format, 0)?;
+            let (firmware, bootloader) = split_flash(&input, device_spec)?;
+
+            let sections = [
+                (firmware_file, &firmware, 0),
+                (
+                    bootloader_file,
+                    &bootloader,
+                    device_spec.platform.firmware_size,
+                ),
+            ];
+            for (file, contents, base_address) in sections {
+                let format = get_format_from_matches(sub_matches, file, "output_format");
+                write_with_format(file, contents, format, base_address)?;
+                eprintln!(
+                    "Wrote {} bytes to {} (MD5: {:x})",
+                    contents.len(),
+                    file,
+                    md5::compute(contents)
+                );
+            }
+        }
+        Some(("merge", sub_matches)) => {
+            let firmware_file = sub_matches
+                .get_one::<String>("firmware_file")
+                .map(|s| s.as_str())
+                .unwrap();
+            let bootloader_file = sub_matches
+                .get_one::<String>("bootloader_file")
+                .map(|s| s.as_str())
+                .unwrap();
+            let output_file = sub_matches
+                .get_one::<String>("output_file")
+                .map(|s| s.as_str())
+                .unwrap();
+
+            let output_format = get_format_from_matches(sub_matches, output_file, "output_format");
+
+            let device_spec = get_device_spec_from_matches(sub_matches);
+
+            let firmware = read_with_format(
+                firmware_file,
+                get_format_from_matches(sub_matches, firmware_file, "input_format"),
+                0,
+            )?;
+            let bootloader = read_with_format(
+                bootloader_file,
+                get_format_from_matches(sub_matches, bootloader_file, "input_format"),
+                device_spec.platform.firmware_size,
+            )?;
+
+            let (image, layout) = merge_flash(&firmware, &bootloader, device_spec)?;
+            if layout == PayloadLayout::ISP {
+                eprintln!("Firmware has the ISP layout, converted it to the JTAG layout");
+            }
+
+            write_with_format(output_file, &image, output_format, 0)?;
+            eprintln!(
+                "Wrote {} bytes to {} (MD5: {:x})",
+                image.len(),
+                output_file,
+                md5::compute(&image)
+            );
+        }
+        Some(("extract-pcap", sub_matches)) => {
+            let input_file = sub_matches
+                .get_one::<String>("input_file")
+                .map(|s| s.as_str())
+                .unwrap();
+
+            let output_file = sub_matches
+                .get_one::<String>("output_file")
+                .map(|s| s.as_str())
+                .unwrap();
+
+            let format = get_format_from_matches(sub_matches, output_file, "format");
+
+            let device_spec = get_device_spec_from_matches(sub_matches);
+
+            let capture = fs::read(input_file).map_err(CLIError::from)?;
+            let reports = extract_feature_reports(&capture).map_err(CLIError::from)?;
+            eprintln!("Found {} feature reports", reports.len());
+
+            let firmware = rebuild_firmware(&reports, device_spec).map_err(CLIError::from)?;
+
+            let digest = md5::compute(&firmware);
+            eprintln!("MD5: {:x}", digest);
+
+            write_with_format(output_file, &firmware, format, 0)?;
+
+            eprintln!(
+                "Successfully extracted {} bytes - {}",
+                firmware.len(),
+                output_file
+            );
+        }
+        Some(("extract-updater", sub_matches)) => {
+            let input_file = sub_matches
+                .get_one::<String>("input_file")
+                .map(|s| s.as_str())
+                .unwrap();
+
+            let output_dir = sub_matches.get_one::<String>("output_dir");
+
+            let format = sub_matches
+                .get_one::<String>("format")
+                .map(|s| Format::from_str(s).unwrap())
+                .unwrap();
+
+            let executable = fs::read(input_file).map_err(CLIError::from)?;
+            let candidates = find_firmware_candidates(&executable).map_err(CLIError::from)?;
+
+            if candidates.is_empty() {
+                eprintln!("No firmware candidates found");
+            }
+
+            for (i, candidate) in candidates.iter().enumerate() {
+                println!(
+                    "#{}: {} @ {:#06x}, {} ({} bytes), MD5: {:x}",
+                    i + 1,
+                    candidate.source,
+                    candidate.offset,
+                    candidate.platform,
+                    candidate.firmware.len(),
+                    md5::compute(&candidate.firmware)
+                );
+
+                if let Some(output_dir) = output_dir {
+                    let extension = match format {
+                        Format::IntelHex => "hex",
+                        Format::Binary => "bin",
+                    };
+                    let output_file = Path::new(output_dir).join(format!(
+                        "candidate-{}-{}.{}",
+                        i + 1,
+                        candidate.platform,
+                        extension
+                    ));
+                    write_with_format(
+                        &output_file.to_string_lossy(),
+                        &candidate.firmware,
+                        format,
+                        0,
+                    )?;
+                }
+            }
         }
         _ => unreachable!(),
     }
@@ -329,7 +1138,10 @@ fn err_main() -> Result<(), CLIError> {
 }
 
 trait DeviceCommand {
+    /// Arguments selecting the device spec, also used by commands working on files only
     fn device_args(self) -> Command;
+    /// Arguments for commands talking to a device over the ISP transport
+    fn transport_args(self) -> Command;
 }
 
 impl DeviceCommand for Command {
@@ -365,6 +1177,29 @@ impl DeviceCommand for Command {
         .arg(arg!(--isp_report_id <USAGE>).value_parser(maybe_hex::<u32>))
         .arg(arg!(--reboot <BOOL>).value_parser(value_parser!(bool)))
     }
+
+    fn transport_args(self) -> Command {
+        self.arg(
+            arg!(--simulate <FLASH_FILE> "use a simulated device backed by a flash image file")
+                .conflicts_with("replay"),
+        )
+        .arg(
+            arg!(--page_retries <NUM> "number of retries for a failed page transfer")
+                .value_parser(value_parser!(usize))
+                .default_value(DEFAULT_PAGE_RETRY_COUNT),
+        )
+        .arg(arg!(--replay <TRACE_FILE> "replay a recorded trace instead of using a device"))
+        .arg(arg!(--dry_run "print the planned steps without sending any feature report"))
+        .arg(arg!(--trace <TRACE_FILE> "record all exchanged feature reports into a trace file"))
+        .arg(
+            arg!(--fault <FAULT> "inject a transport fault for testing, e.g. drop@17, delay:500@3, corrupt@5, disconnect@10")
+                .value_parser(value_parser!(Fault))
+                .action(ArgAction::Append)
+                .requires("simulated_transport"),
+        )
+        // faults are only injected into a simulated or replayed device, never into a real one
+        .group(ArgGroup::new("simulated_transport").args(["simulate", "replay"]))
+    }
 }
 
 fn get_format_from_matches(
@@ -468,7 +1303,161 @@ fn get_device_spec_from_matches(sub_matches: &ArgMatches) -> DeviceSpec {
     device_spec
 }
 
-fn read_with_format(file: &str, format: Format) -> Result<Vec<u8>, CLIError> {
+/// Starts a dry run plan with the steps `fetch_isp_device` would take to connect
+fn plan_isp_device(sub_matches: &ArgMatches, device_spec: DeviceSpec, retry_count: usize) -> Plan {
+    let page_retries = sub_matches
+        .get_one::<usize>("page_retries")
+        .map(|s| s.to_owned())
+        .unwrap();
+    let mut plan = Plan::new(device_spec).with_page_retries(page_retries);
+    if let Some(flash_file) = sub_matches.get_one::<String>("simulate") {
+        plan.step(format!(
+            "Open a simulated device with flash image {}",
+            flash_file
+        ));
+    } else if let Some(trace_file) = sub_matches.get_one::<String>("replay") {
+        plan.step(format!("Replay trace {}", trace_file));
+    } else {
+        plan.step(DeviceSelector::describe_isp_switch(
+            device_spec,
+            retry_count,
+        ));
+    }
+    if let Some(trace_file) = sub_matches.get_one::<String>("trace") {
+        plan.step(format!("Record all feature reports to {}", trace_file));
+    }
+    plan
+}
+
+fn fetch_isp_device(
+    sub_matches: &ArgMatches,
+    device_spec: DeviceSpec,
+    retry_count: usize,
+) -> Result<ISPDevice, CLIError> {
+    let faults: Vec<Fault> = sub_matches
+        .get_many::<Fault>("fault")
+        .map(|faults| faults.copied().collect())
+        .unwrap_or_default();
+
+    // created first so the trace also holds the ISP mode switch
+    let recorder = match sub_matches.get_one::<String>("trace") {
+        Some(trace_file) => {
+            eprintln!("Recording trace to {}", trace_file);
+            Some(Rc::new(TraceRecorder::create(trace_file)?))
+        }
+        None => None,
+    };
+
+    let device = if let Some(flash_file) = sub_matches.get_one::<String>("simulate") {
+        eprintln!("Simulating device with flash image {}", flash_file);
+        let transport = SimulatedTransport::open(flash_file, device_spec.platform)?;
+        ISPDevice::new(device_spec, Box::new(transport))
+    } else if let Some(trace_file) = sub_matches.get_one::<String>("replay") {
+        eprintln!("Replaying trace {}", trace_file);
+        // a replay starts at the bootloader, past the switch into it
+        let transport = ReplayTransport::open(trace_file)?.skip_sent(&ISP_MODE_REPORT);
+        ISPDevice::new(device_spec, Box::new(transport))
+    } else {
+        let mut ds = DeviceSelector::new().map_err(CLIError::DeviceSelectorError)?;
+        if let Some(recorder) = &recorder {
+            ds = ds.with_recorder(recorder.clone());
+        }
+        ds.try_fetch_isp_device(device_spec, retry_count)
+            .map_err(CLIError::from)?
+    };
+
+    let page_retries = sub_matches
+        .get_one::<usize>("page_retries")
+        .map(|s| s.to_owned())
+        .unwrap();
+    let device = device.with_page_retries(page_retries);
+
+    let device = if faults.is_empty() {
+        device
+    } else {
+        device.map_transport(|transport| Box::new(FaultInjector::new(transport, faults)))
+    };
+
+    // the recorder wraps the fault injector, so the trace holds what the tool saw, faults included
+    let Some(recorder) = recorder else {
+        return Ok(device);
+    };
+    Ok(device.map_transport(|transport| Box::new(RecordingTransport::new(transport, recorder))))
+}
+
+/// Converts a JTAG layout payload to the ISP layout and rewrites an AJMP or SJMP reset vector as
+/// LJMP, as expected by the bootloader
+fn prepare_isp_payload(firmware: &mut Vec<u8>, device_spec: DeviceSpec) -> Result<(), CLIError> {
+    if detect_layout(firmware, device_spec) == Some(PayloadLayout::JTAG) {
+        eprintln!("Payload has the JTAG layout, converting it to the ISP layout");
+        convert_to_isp_payload(firmware, device_spec)?;
+        firmware.truncate(device_spec.platform.firmware_size);
+    }
+
+    if let Some(jump @ (Jump::AJMP(_) | Jump::SJMP(_))) = Jump::decode(firmware, 0) {
+        normalize_reset_vector(firmware)?;
+        eprintln!(
+            "Rewriting the reset vector {} as LJMP {:#06x}",
+            jump,
+            jump.target()
+        );
+    }
+    Ok(())
+}
+
+fn backup_path(backup_dir: &Path, device_spec: DeviceSpec, timestamp: &str) -> PathBuf {
+    backup_dir.join(format!(
+        "backup-{:04x}-{:04x}-{}.bin",
+        device_spec.vendor_id, device_spec.product_id, timestamp
+    ))
+}
+
+/// Saves the firmware read from a device before it is overwritten
+fn save_backup(backup_dir: &Path, device_spec: DeviceSpec, current: &[u8]) -> Result<(), CLIError> {
+    let backup_file = backup_path(backup_dir, device_spec, &utc_timestamp(SystemTime::now()));
+    write_with_format(&backup_file.to_string_lossy(), current, Format::Binary, 0)?;
+    eprintln!(
+        "Saved backup to {} (MD5: {:x})",
+        backup_file.display(),
+        md5::compute(current)
+    );
+    Ok(())
+}
+
+/// Writes the backup back after a failed write, asking first unless `auto_rollback` is set.
+///
+/// Returns `Ok` if the rollback succeeded or was declined, the failed write is reported by the
+/// caller either way.
+fn rollback(
+    device: ISPDevice,
+    device_spec: DeviceSpec,
+    mut backup: Vec<u8>,
+    journal_path: &Path,
+    auto_rollback: bool,
+) -> Result<(), CLIError> {
+    if !auto_rollback {
+        eprintln!("Use --auto_rollback to skip confirmation");
+        let confirmation = Confirm::new()
+            .with_prompt("Do you want to write the backup back?")
+            .default(true)
+            .interact()
+            .unwrap();
+
+        if !confirmation {
+            return Ok(());
+        }
+    }
+
+    eprintln!("Rolling back...");
+    let journal = Journal::create(journal_path, &backup, device_spec)?;
+    let device = device.with_journal(journal);
+    device.write_cycle(&mut backup).map_err(CLIError::from)?;
+    eprintln!("Successfully rolled back to the backup");
+    Ok(())
+}
+
+/// Reads a file holding data that belongs at `base_address`, e.g. the bootloader section
+fn read_with_format(file: &str, format: Format, base_address: usize) -> Result<Vec<u8>, CLIError> {
     let mut file = fs::File::open(file).map_err(CLIError::from)?;
     let mut file_buf = Vec::new();
     file.read_to_end(&mut file_buf).map_err(CLIError::from)?;
@@ -476,16 +1465,22 @@ fn read_with_format(file: &str, format: Format) -> Result<Vec<u8>, CLIError> {
     match format {
         Format::IntelHex => {
             let file_str = String::from_utf8_lossy(&file_buf[..]);
-            from_ihex(&file_str, 0x10000).map_err(CLIError::from)
+            from_ihex(&file_str, base_address, 0x10000 - base_address).map_err(CLIError::from)
         }
         Format::Binary => Ok(file_buf),
     }
 }
 
-fn write_with_format(file: &str, data: &[u8], format: Format) -> Result<(), CLIError> {
+/// Writes data that belongs at `base_address`, IHEX records carry the real addresses
+fn write_with_format(
+    file: &str,
+    data: &[u8],
+    format: Format,
+    base_address: usize,
+) -> Result<(), CLIError> {
     match format {
         Format::IntelHex => {
-            let ihex = to_ihex(data).map_err(CLIError::from)?;
+            let ihex = to_ihex(data, base_address).map_err(CLIError::from)?;
             fs::write(file, ihex).map_err(CLIError::from)
         }
         Format::Binary => fs::write(file, data).map_err(CLIError::from),
@@ -0,0 +1,420 @@
+use std::collections::HashSet;
+
+use log::{debug, warn};
+use thiserror::Error;
+
+use crate::{
+    device_spec::DeviceSpec,
+    isp_device::{CMD_ERASE, CMD_INIT_WRITE, REPORT_ID_CMD, REPORT_ID_XFER, XFER_WRITE_PAGE},
+    util::clear_reset_vector_copy,
+};
+
+#[cfg(test)]
+use crate::{device_spec::DEVICE_BASE_SH68F90, util::test_payload};
+
+const PCAP_MAGIC_USEC: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NSEC: u32 = 0xa1b23c4d;
+const PCAPNG_BLOCK_SHB: u32 = 0x0a0d0d0a;
+const PCAPNG_BLOCK_IDB: u32 = 0x00000001;
+const PCAPNG_BLOCK_SPB: u32 = 0x00000003;
+const PCAPNG_BLOCK_EPB: u32 = 0x00000006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+
+const LINKTYPE_USB_LINUX: u32 = 189;
+const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+const LINKTYPE_USBPCAP: u32 = 249;
+
+const USB_TRANSFER_CONTROL: u8 = 2;
+const USBPCAP_STAGE_SETUP: u8 = 0;
+const USBPCAP_STAGE_DATA: u8 = 1;
+
+/// bmRequestType of a host-to-device, class, interface request
+const HID_REQUEST_TYPE_OUT: u8 = 0x21;
+const HID_SET_REPORT: u8 = 0x09;
+const HID_REPORT_TYPE_FEATURE: u8 = 0x03;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PcapError {
+    #[error("Not a pcap or pcapng capture")]
+    UnknownFormat,
+    #[error("Capture is truncated")]
+    Truncated,
+    #[error("Unsupported link type {0}, expected a usbmon or USBPcap capture")]
+    UnsupportedLinkType(u32),
+    #[error("No ISP page writes found in capture")]
+    NoWritesFound,
+}
+
+/// Bounds checked reader for fields in either byte order
+struct Fields<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Fields<'a> {
+    fn new(data: &'a [u8], big_endian: bool) -> Self {
+        Self { data, big_endian }
+    }
+
+    fn bytes(&self, offset: usize, length: usize) -> Result<&'a [u8], PcapError> {
+        self.data
+            .get(offset..offset + length)
+            .ok_or(PcapError::Truncated)
+    }
+
+    fn u8(&self, offset: usize) -> Result<u8, PcapError> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, PcapError> {
+        let bytes = self.bytes(offset, 2)?.try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, PcapError> {
+        let bytes = self.bytes(offset, 4)?.try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn u64(&self, offset: usize) -> Result<u64, PcapError> {
+        let bytes = self.bytes(offset, 8)?.try_into().unwrap();
+        Ok(match self.big_endian {
+            true => u64::from_be_bytes(bytes),
+            false => u64::from_le_bytes(bytes),
+        })
+    }
+}
+
+/// A captured packet along with the link type of the interface it was captured on
+struct Packet<'a> {
+    link_type: u32,
+    big_endian: bool,
+    data: &'a [u8],
+}
+
+fn read_pcap(capture: &[u8]) -> Result<Vec<Packet<'_>>, PcapError> {
+    let magic = Fields::new(capture, false).u32(0)?;
+    let big_endian = match magic {
+        PCAP_MAGIC_USEC | PCAP_MAGIC_NSEC => false,
+        _ if magic.swap_bytes() == PCAP_MAGIC_USEC || magic.swap_bytes() == PCAP_MAGIC_NSEC => true,
+        PCAPNG_BLOCK_SHB => return read_pcapng(capture),
+        _ => return Err(PcapError::UnknownFormat),
+    };
+    let fields = Fields::new(capture, big_endian);
+    let link_type = fields.u32(20)?;
+
+    let mut packets = vec![];
+    let mut offset = 24;
+    while offset < capture.len() {
+        let captured_length = fields.u32(offset + 8)? as usize;
+        packets.push(Packet {
+            link_type,
+            big_endian,
+            data: fields.bytes(offset + 16, captured_length)?,
+        });
+        offset += 16 + captured_length;
+    }
+    Ok(packets)
+}
+
+fn read_pcapng(capture: &[u8]) -> Result<Vec<Packet<'_>>, PcapError> {
+    let mut packets = vec![];
+    let mut link_types: Vec<u32> = vec![];
+    let mut big_endian = false;
+    let mut offset = 0;
+    while offset < capture.len() {
+        let block_type = Fields::new(capture, big_endian).u32(offset)?;
+        if block_type == PCAPNG_BLOCK_SHB {
+            // each section may use a different byte order and has its own interfaces
+            let magic = Fields::new(capture, false).u32(offset + 8)?;
+            big_endian = magic != PCAPNG_BYTE_ORDER_MAGIC;
+            link_types.clear();
+        }
+        let fields = Fields::new(capture, big_endian);
+        let block_length = fields.u32(offset + 4)? as usize;
+        if block_length < 12 {
+            return Err(PcapError::Truncated);
+        }
+        let block = Fields::new(fields.bytes(offset, block_length)?, big_endian);
+
+        match block_type {
+            PCAPNG_BLOCK_IDB => link_types.push(block.u16(8)? as u32),
+            PCAPNG_BLOCK_EPB => {
+                let interface = block.u32(8)? as usize;
+                let captured_length = block.u32(20)? as usize;
+                packets.push(Packet {
+                    link_type: *link_types.get(interface).ok_or(PcapError::Truncated)?,
+                    big_endian,
+                    data: block.bytes(28, captured_length)?,
+                });
+            }
+            PCAPNG_BLOCK_SPB => {
+                let captured_length = (block.u32(8)? as usize).min(block_length - 16);
+                packets.push(Packet {
+                    link_type: *link_types.first().ok_or(PcapError::Truncated)?,
+                    big_endian,
+                    data: block.bytes(12, captured_length)?,
+                });
+            }
+            _ => {}
+        }
+        offset += block_length;
+    }
+    Ok(packets)
+}
+
+fn is_set_feature_report(setup: &[u8]) -> bool {
+    setup[0] == HID_REQUEST_TYPE_OUT
+        && setup[1] == HID_SET_REPORT
+        && setup[3] == HID_REPORT_TYPE_FEATURE
+}
+
+/// Returns the payload of a SET_REPORT(Feature) request captured through usbmon
+fn usbmon_set_report<'a>(
+    packet: &Packet<'a>,
+    header_length: usize,
+) -> Result<Option<&'a [u8]>, PcapError> {
+    let fields = Fields::new(packet.data, packet.big_endian);
+    let event_type = fields.u8(8)?;
+    let transfer_type = fields.u8(9)?;
+    let setup_flag = fields.u8(14)?;
+    // only submissions carry the setup packet and data of OUT transfers
+    if event_type != b'S' || transfer_type != USB_TRANSFER_CONTROL || setup_flag != 0 {
+        return Ok(None);
+    }
+    if !is_set_feature_report(fields.bytes(40, 8)?) {
+        return Ok(None);
+    }
+    let captured_length = fields.u32(36)? as usize;
+    Ok(Some(fields.bytes(header_length, captured_length)?))
+}
+
+/// Extracts the payloads of all SET_REPORT(Feature) requests found in a capture, in order
+pub fn extract_feature_reports(capture: &[u8]) -> Result<Vec<Vec<u8>>, PcapError> {
+    let mut reports = vec![];
+    // USBPcap may log the data stage of a control transfer as a separate packet
+    let mut pending_setups: HashSet<u64> = HashSet::new();
+
+    for packet in read_pcap(capture)? {
+        let report = match packet.link_type {
+            LINKTYPE_USB_LINUX => usbmon_set_report(&packet, 48)?,
+            LINKTYPE_USB_LINUX_MMAPPED => usbmon_set_report(&packet, 64)?,
+            LINKTYPE_USBPCAP => {
+                let fields = Fields::new(packet.data, false);
+                let header_length = fields.u16(0)? as usize;
+                let irp_id = fields.u64(2)?;
+                let info = fields.u8(16)?;
+                let transfer_type = fields.u8(22)?;
+                // bit 0 of info is set for completions
+                if transfer_type != USB_TRANSFER_CONTROL || info & 0x01 != 0 {
+                    continue;
+                }
+                let data = packet
+                    .data
+                    .get(header_length..)
+                    .ok_or(PcapError::Truncated)?;
+                match fields.u8(27)? {
+                    USBPCAP_STAGE_SETUP if data.len() >= 8 && is_set_feature_report(data) => {
+                        if data.len() > 8 {
+                            Some(&data[8..])
+                        } else {
+                            pending_setups.insert(irp_id);
+                            None
+                        }
+                    }
+                    USBPCAP_STAGE_DATA if pending_setups.remove(&irp_id) => Some(data),
+                    _ => None,
+                }
+            }
+            link_type => return Err(PcapError::UnsupportedLinkType(link_type)),
+        };
+        if let Some(report) = report {
+            reports.push(report.to_vec());
+        }
+    }
+    Ok(reports)
+}
+
+/// Rebuilds the firmware written by an ISP session from its feature reports.
+///
+/// The result has the same layout as the payload verified by `write_cycle`, with the redirected
+/// reset vector copy at <firmware_size-4> cleared.
+pub fn rebuild_firmware(
+    reports: &[Vec<u8>],
+    device_spec: DeviceSpec,
+) -> Result<Vec<u8>, PcapError> {
+    let firmware_size = device_spec.platform.firmware_size;
+    let mut firmware = vec![0; firmware_size];
+    let mut write_addr = 0;
+    let mut written = 0;
+
+    for report in reports {
+        match report.as_slice() {
+            [REPORT_ID_CMD, CMD_ERASE, ..] => {
+                if written > 0 {
+                    warn!("Capture contains multiple write sessions, using the last one");
+                }
+                firmware.fill(0);
+                written = 0;
+            }
+            [REPORT_ID_CMD, CMD_INIT_WRITE, lo, hi, ..] => {
+                write_addr = u16::from_le_bytes([*lo, *hi]) as usize;
+                debug!("Write initialized @ {:#06x}", write_addr);
+            }
+            [REPORT_ID_XFER, XFER_WRITE_PAGE, page @ ..] => {
+                let end = (write_addr + page.len()).min(firmware_size);
+                if end < write_addr + page.len() {
+                    warn!(
+                        "Ignoring data written past the firmware section @ {:#06x}",
+                        end
+                    );
+                }
+                if write_addr < end {
+                    firmware[write_addr..end].copy_from_slice(&page[..end - write_addr]);
+                }
+                debug!("Page written @ {:#06x}", write_addr);
+                write_addr += page.len();
+                written += 1;
+            }
+            _ => {}
+        }
+    }
+
+    if written == 0 {
+        return Err(PcapError::NoWritesFound);
+    }
+
+    clear_reset_vector_copy(&mut firmware, device_spec);
+    Ok(firmware)
+}
+
+#[cfg(test)]
+fn usbmon_packet(event_type: u8, setup: [u8; 8], data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0; 48];
+    packet[8] = event_type;
+    packet[9] = USB_TRANSFER_CONTROL;
+    packet[14] = if event_type == b'S' { 0 } else { b'-' };
+    packet[36..40].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    packet[40..48].copy_from_slice(&setup);
+    packet.extend_from_slice(data);
+    packet
+}
+
+#[cfg(test)]
+fn set_report_setup(report_id: u8) -> [u8; 8] {
+    [0x21, 0x09, report_id, 0x03, 0x01, 0x00, 0x06, 0x00]
+}
+
+#[test]
+fn test_extract_pcap_usbmon() {
+    let mut capture = vec![];
+    capture.extend_from_slice(&PCAP_MAGIC_USEC.to_le_bytes());
+    capture.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0]);
+    capture.extend_from_slice(&LINKTYPE_USB_LINUX.to_le_bytes());
+
+    let report = [0x05, 0x57, 0x00, 0x00, 0x00, 0x00];
+    let get_report = [0xa1, 0x01, 0x06, 0x03, 0x01, 0x00, 0x02, 0x08];
+    for packet in [
+        usbmon_packet(b'S', set_report_setup(0x05), &report),
+        usbmon_packet(b'C', set_report_setup(0x05), &[]),
+        usbmon_packet(b'S', get_report, &[]),
+    ] {
+        capture.extend_from_slice(&[0; 8]);
+        capture.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        capture.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        capture.extend_from_slice(&packet);
+    }
+
+    assert_eq!(extract_feature_reports(&capture), Ok(vec![report.to_vec()]));
+}
+
+#[test]
+fn test_extract_pcapng_usbpcap() {
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let length = (12 + body.len()) as u32;
+        let mut block = vec![];
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&length.to_le_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&length.to_le_bytes());
+        block
+    }
+
+    fn usbpcap_packet(irp_id: u64, stage: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 28];
+        packet[0..2].copy_from_slice(&28u16.to_le_bytes());
+        packet[2..10].copy_from_slice(&irp_id.to_le_bytes());
+        packet[22] = USB_TRANSFER_CONTROL;
+        packet[23..27].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        packet[27] = stage;
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    let report = [0x05, 0x45, 0x00, 0x00, 0x00, 0x00];
+    let mut capture = vec![];
+    let mut shb = PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+    shb.extend_from_slice(&[1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    capture.extend(block(PCAPNG_BLOCK_SHB, &shb));
+    capture.extend(block(PCAPNG_BLOCK_IDB, &[249, 0, 0, 0, 0, 0, 0, 0]));
+    for packet in [
+        usbpcap_packet(1, USBPCAP_STAGE_SETUP, &set_report_setup(0x05)),
+        usbpcap_packet(1, USBPCAP_STAGE_DATA, &report),
+        usbpcap_packet(2, USBPCAP_STAGE_DATA, &[0x01, 0x02]),
+    ] {
+        let mut epb = vec![0; 20];
+        epb[12..16].copy_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb[16..20].copy_from_slice(&(packet.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&packet);
+        // pad to 32 bits as required by pcapng
+        epb.resize(epb.len().div_ceil(4) * 4, 0);
+        capture.extend(block(PCAPNG_BLOCK_EPB, &epb));
+    }
+
+    assert_eq!(extract_feature_reports(&capture), Ok(vec![report.to_vec()]));
+}
+
+#[test]
+fn test_extract_pcap_unknown_format() {
+    assert_eq!(
+        extract_feature_reports(&[0x00; 32]),
+        Err(PcapError::UnknownFormat)
+    );
+}
+
+#[test]
+fn test_rebuild_firmware() {
+    let page_size = DEVICE_BASE_SH68F90.platform.page_size;
+    let mut firmware = test_payload(DEVICE_BASE_SH68F90);
+    firmware.copy_within(1..3, DEVICE_BASE_SH68F90.platform.firmware_size - 4);
+
+    let mut reports = vec![
+        vec![0x05, 0x45, 0x00, 0x00, 0x00, 0x00],
+        vec![0x05, 0x57, 0x00, 0x00, 0x00, 0x00],
+    ];
+    for page in firmware.chunks(page_size) {
+        let mut report = vec![0x06, 0x77];
+        report.extend_from_slice(page);
+        reports.push(report);
+    }
+    reports.push(vec![0x05, 0x55, 0x00, 0x00, 0x00, 0x00]);
+
+    let rebuilt = rebuild_firmware(&reports, DEVICE_BASE_SH68F90).unwrap();
+    firmware[0xeffc..0xeffe].fill(0);
+    assert_eq!(rebuilt, firmware);
+}
+
+#[test]
+fn test_rebuild_firmware_no_writes() {
+    let reports = vec![vec![0x05, 0x52, 0x00, 0x00, 0x00, 0x00]];
+    assert_eq!(
+        rebuild_firmware(&reports, DEVICE_BASE_SH68F90),
+        Err(PcapError::NoWritesFound)
+    );
+}
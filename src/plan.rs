@@ -0,0 +1,406 @@
+use std::{
+    fmt,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    isp_device::{
+        CMD_ENABLE_FIRMWARE, CMD_ERASE, CMD_INIT_READ, CMD_INIT_WRITE, CMD_REBOOT, REPORT_ID_CMD,
+        REPORT_ID_XFER, XFER_READ_PAGE, XFER_WRITE_PAGE,
+    },
+    DeviceSpec,
+};
+
+#[cfg(test)]
+use std::{cell::RefCell, rc::Rc};
+
+#[cfg(test)]
+use hidapi::HidError;
+
+#[cfg(test)]
+use crate::{
+    device_spec::{DEVICE_BASE_SH68F881, DEVICE_BASE_SH68F90},
+    simulator::SimulatedBootloader,
+    to_hex_string,
+    transport::{ISPTransport, SimulatedTransport},
+    util::test_payload,
+    ISPDevice,
+};
+
+/// The steps a device command would take, printed by `--dry_run` instead of running them.
+///
+/// Steps mirror what `ISPDevice` sends, so a plan can be reviewed before touching a device. The
+/// tests below check the reports of every step against those sent to a simulated device.
+pub struct Plan {
+    device_spec: DeviceSpec,
+    page_retries: usize,
+    journal: Option<PathBuf>,
+    steps: Vec<String>,
+}
+
+impl Plan {
+    pub fn new(device_spec: DeviceSpec) -> Self {
+        Self {
+            device_spec,
+            page_retries: 0,
+            journal: None,
+            steps: vec![],
+        }
+    }
+
+    /// Mentions the retries of failed page transfers, as set by `ISPDevice::with_page_retries`
+    pub fn with_page_retries(mut self, page_retries: usize) -> Self {
+        self.page_retries = page_retries;
+        self
+    }
+
+    /// Records `write_cycle` progress in a journal, as set by `ISPDevice::with_journal`
+    pub fn with_journal(mut self, path: &Path) -> Self {
+        self.journal = Some(path.to_path_buf());
+        self
+    }
+
+    pub fn step(&mut self, step: impl Into<String>) {
+        self.steps.push(step.into());
+    }
+
+    pub fn enable_firmware(&mut self) {
+        self.step(format!(
+            "Enable the firmware (report {})",
+            command(CMD_ENABLE_FIRMWARE, 0)
+        ));
+    }
+
+    /// Reads as `ISPDevice::read_range` does, widened to page boundaries
+    pub fn read_range(&mut self, start_addr: usize, length: usize, passes: usize) {
+        self.enable_firmware();
+
+        let page_size = self.device_spec.platform.page_size;
+        let aligned_start = start_addr - start_addr % page_size;
+        let aligned_end = (start_addr + length).div_ceil(page_size) * page_size;
+        self.read_pages(aligned_start..aligned_end, passes);
+        if aligned_start != start_addr || aligned_end != start_addr + length {
+            self.step(format!(
+                "Keep {:#06x}-{:#06x} ({} bytes) of the pages read",
+                start_addr,
+                start_addr + length - 1,
+                length
+            ));
+        }
+    }
+
+    /// Reads whole pages, without enabling the firmware first
+    pub fn read_pages(&mut self, range: Range<usize>, passes: usize) {
+        self.step(format!(
+            "Start reading @ {:#06x} (report {})",
+            range.start,
+            command(CMD_INIT_READ, range.start)
+        ));
+        let passes = if passes > 1 {
+            format!(", {} times each with majority voting", passes)
+        } else {
+            String::new()
+        };
+        self.step(format!(
+            "Read {} (report {:02x} {:02x}){}{}",
+            self.pages(range),
+            REPORT_ID_XFER,
+            XFER_READ_PAGE,
+            passes,
+            self.retries()
+        ));
+    }
+
+    pub fn erase(&mut self) {
+        self.step(format!(
+            "Erase the firmware section, the bootloader is kept and the reset vector jumps to ISP (report {})",
+            command(CMD_ERASE, 0)
+        ));
+    }
+
+    /// Writes as `ISPDevice::write_cycle` does, `verify_erase` checks that flash is blank first
+    pub fn write_cycle(&mut self, firmware: &[u8], verify_erase: bool) {
+        let firmware_size = self.device_spec.platform.firmware_size;
+        let slot = firmware_size - 4..firmware_size - 2;
+        if let Some(journal) = &self.journal {
+            self.step(format!(
+                "Create journal {} with a copy of the payload",
+                journal.display()
+            ));
+        }
+        self.step(format!(
+            "Copy the reset vector address {:02x} {:02x} from 0x0001-0x0002 to {:#06x}-{:#06x} (firmware_size-4), where the bootloader looks for it",
+            firmware[1],
+            firmware[2],
+            slot.start,
+            slot.end - 1
+        ));
+        self.erase();
+        if verify_erase {
+            self.read_pages(0..firmware_size, 1);
+            self.step("Check that the firmware section is blank");
+        }
+        self.step(format!(
+            "Start writing @ 0x0000 (report {})",
+            command(CMD_INIT_WRITE, 0)
+        ));
+        let journal = match &self.journal {
+            Some(_) => ", recording each written page in the journal",
+            None => "",
+        };
+        self.step(format!(
+            "Write {} (report {:02x} {:02x}){}{}",
+            self.pages(0..firmware_size),
+            REPORT_ID_XFER,
+            XFER_WRITE_PAGE,
+            self.retries(),
+            journal
+        ));
+        self.step(format!(
+            "Clear {:#06x}-{:#06x} in the payload, it always reads back as zeroes",
+            slot.start,
+            slot.end - 1
+        ));
+        self.read_pages(0..firmware_size, 1);
+        self.step("Verify the read back firmware against the payload");
+        self.enable_firmware();
+        self.maybe_reboot();
+        if let Some(journal) = &self.journal {
+            self.step(format!("Remove journal {}", journal.display()));
+        }
+    }
+
+    pub fn reboot(&mut self) {
+        self.step(format!(
+            "Reboot into the firmware (report {})",
+            command(CMD_REBOOT, 0)
+        ));
+    }
+
+    /// Reboots if the device spec asks for it, as `ISPDevice` does after reading or writing
+    pub fn maybe_reboot(&mut self) {
+        if self.device_spec.reboot {
+            self.reboot();
+        } else {
+            self.step("Do not reboot, the device stays in ISP mode (reboot=false)");
+        }
+    }
+
+    fn retries(&self) -> String {
+        match self.page_retries {
+            0 => String::new(),
+            1 => ", retrying a failed page once".to_string(),
+            retries => format!(", retrying a failed page up to {} times", retries),
+        }
+    }
+
+    fn pages(&self, range: Range<usize>) -> String {
+        let page_size = self.device_spec.platform.page_size;
+        let addrs: Vec<String> = range
+            .clone()
+            .step_by(page_size)
+            .map(|addr| format!("{:#06x}", addr))
+            .collect();
+        format!(
+            "{} {} of {} bytes @ {}",
+            addrs.len(),
+            if addrs.len() == 1 { "page" } else { "pages" },
+            page_size,
+            addrs.join(", ")
+        )
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Dry run, no feature report will be sent. Plan:")?;
+        for (i, step) in self.steps.iter().enumerate() {
+            writeln!(f, "{:>3}. {}", i + 1, step)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a command report the way `ISPDevice` builds it
+fn command(cmd: u8, addr: usize) -> String {
+    format!(
+        "{:02x} {:02x} {:02x} {:02x} 00 00",
+        REPORT_ID_CMD,
+        cmd,
+        addr & 0xff,
+        addr >> 8
+    )
+}
+
+#[test]
+fn test_plan_read_range() {
+    let mut plan = Plan::new(DEVICE_BASE_SH68F90);
+    plan.read_range(0x0810, 0x10, 1);
+
+    assert_eq!(
+        plan.steps,
+        [
+            "Enable the firmware (report 05 55 00 00 00 00)",
+            "Start reading @ 0x0800 (report 05 52 00 08 00 00)",
+            "Read 1 page of 2048 bytes @ 0x0800 (report 06 72)",
+            "Keep 0x0810-0x081f (16 bytes) of the pages read",
+        ]
+    );
+}
+
+#[test]
+fn test_plan_write_cycle() {
+    let device_spec = DeviceSpec {
+        reboot: false,
+        ..DEVICE_BASE_SH68F881
+    };
+    let firmware = test_payload(device_spec);
+
+    let mut plan = Plan::new(device_spec);
+    plan.write_cycle(&firmware, false);
+
+    assert_eq!(plan.steps.len(), 10);
+    assert_eq!(
+        plan.steps[0],
+        "Copy the reset vector address 00 66 from 0x0001-0x0002 to 0x6ffc-0x6ffd (firmware_size-4), where the bootloader looks for it"
+    );
+    assert!(plan.steps[3].starts_with("Write 14 pages of 2048 bytes @ 0x0000, 0x0800,"));
+    assert!(plan.steps[3].ends_with(", 0x6800 (report 06 77)"));
+    assert_eq!(
+        plan.steps[9],
+        "Do not reboot, the device stays in ISP mode (reboot=false)"
+    );
+}
+
+/// Logs the reports exchanged with a simulated bootloader the way plan steps show them, commands
+/// in full and page transfers by report id and type
+#[cfg(test)]
+struct ReportLog(SimulatedTransport, Rc<RefCell<Vec<String>>>);
+
+#[cfg(test)]
+impl ISPTransport for ReportLog {
+    fn send_feature_report(&self, data: &[u8]) -> Result<(), HidError> {
+        let shown = if data[0] == REPORT_ID_CMD {
+            data
+        } else {
+            &data[..2]
+        };
+        self.1
+            .borrow_mut()
+            .push(to_hex_string(shown).to_lowercase());
+        self.0.send_feature_report(data)
+    }
+
+    fn get_feature_report(&self, buf: &mut [u8]) -> Result<usize, HidError> {
+        let size = self.0.get_feature_report(buf)?;
+        self.1
+            .borrow_mut()
+            .push(to_hex_string(&buf[..2]).to_lowercase());
+        Ok(size)
+    }
+}
+
+/// Runs `run` against a simulated device with blank flash and returns the reports it exchanged
+#[cfg(test)]
+fn device_reports(device_spec: DeviceSpec, run: impl FnOnce(ISPDevice)) -> Vec<String> {
+    let reports = Rc::new(RefCell::new(vec![]));
+    let bootloader = SimulatedBootloader::with_flash(
+        vec![0x00; device_spec.total_flash_size()],
+        device_spec.platform.firmware_size,
+    );
+    let transport = ReportLog(SimulatedTransport::new(bootloader), reports.clone());
+    run(ISPDevice::new(device_spec, Box::new(transport)));
+    reports.take()
+}
+
+/// Expands the reports named in the plan steps, page transfers once per page
+#[cfg(test)]
+fn planned_reports(plan: &Plan) -> Vec<String> {
+    plan.steps
+        .iter()
+        .flat_map(|step| {
+            let Some((_, report)) = step.split_once("(report ") else {
+                return vec![];
+            };
+            let report = report.split_once(')').unwrap().0;
+            let count = match step.split(' ').take(2).collect::<Vec<_>>()[..] {
+                ["Read" | "Write", pages] => pages.parse().unwrap(),
+                _ => 1,
+            };
+            vec![report.to_string(); count]
+        })
+        .collect()
+}
+
+#[test]
+fn test_plan_matches_device_read_range_cycle() {
+    let mut plan = Plan::new(DEVICE_BASE_SH68F90);
+    plan.read_range(0x0810, 0x1000, 1);
+    plan.maybe_reboot();
+
+    let reports = device_reports(DEVICE_BASE_SH68F90, |device| {
+        device.read_range_cycle(0x0810, 0x1000).unwrap();
+    });
+
+    assert_eq!(planned_reports(&plan), reports);
+}
+
+#[test]
+fn test_plan_matches_device_write_cycle() {
+    for verify_erase in [false, true] {
+        let mut firmware = test_payload(DEVICE_BASE_SH68F881);
+
+        let mut plan = Plan::new(DEVICE_BASE_SH68F881);
+        plan.write_cycle(&firmware, verify_erase);
+
+        let reports = device_reports(DEVICE_BASE_SH68F881, |device| {
+            let device = device.with_erase_verification(verify_erase);
+            device.write_cycle(&mut firmware).unwrap();
+        });
+
+        assert_eq!(planned_reports(&plan), reports);
+    }
+}
+
+#[test]
+fn test_plan_matches_device_commands() {
+    let mut plan = Plan::new(DEVICE_BASE_SH68F90);
+    plan.read_pages(0..DEVICE_BASE_SH68F90.platform.firmware_size, 1);
+    plan.erase();
+    plan.enable_firmware();
+    plan.reboot();
+
+    let reports = device_reports(DEVICE_BASE_SH68F90, |device| {
+        device.blank_check().unwrap();
+        device.erase().unwrap();
+        device.enable_firmware().unwrap();
+        device.reboot();
+    });
+
+    assert_eq!(planned_reports(&plan), reports);
+}
+
+#[test]
+fn test_plan_retries_and_journal() {
+    let device_spec = DeviceSpec {
+        reboot: false,
+        ..DEVICE_BASE_SH68F881
+    };
+    let firmware = test_payload(device_spec);
+
+    let mut plan = Plan::new(device_spec)
+        .with_page_retries(3)
+        .with_journal(Path::new("write.journal"));
+    plan.write_cycle(&firmware, false);
+
+    assert_eq!(
+        plan.steps[0],
+        "Create journal write.journal with a copy of the payload"
+    );
+    assert!(plan.steps[4].ends_with(
+        "(report 06 77), retrying a failed page up to 3 times, recording each written page in the journal"
+    ));
+    assert!(plan.steps[7].ends_with("(report 06 72), retrying a failed page up to 3 times"));
+    assert_eq!(plan.steps.last().unwrap(), "Remove journal write.journal");
+}
@@ -0,0 +1,213 @@
+use thiserror::Error;
+
+const COMMAND_LENGTH: usize = 6;
+
+const REPORT_ID_CMD: u8 = 0x05;
+const REPORT_ID_XFER: u8 = 0x06;
+
+const CMD_ENABLE_FIRMWARE: u8 = 0x55;
+const CMD_INIT_READ: u8 = 0x52;
+const CMD_INIT_WRITE: u8 = 0x57;
+const CMD_ERASE: u8 = 0x45;
+const CMD_REBOOT: u8 = 0x5a;
+
+const XFER_READ_PAGE: u8 = 0x72;
+const XFER_WRITE_PAGE: u8 = 0x77;
+
+const OPCODE_LJMP: u8 = 0x02;
+
+/// Value of a flash byte after an erase operation
+pub const ERASED_BYTE: u8 = 0x00;
+
+#[derive(Debug, Clone, Error, PartialEq)]
+pub enum SimulatorError {
+    #[error("Device is disconnected")]
+    Disconnected,
+    #[error("Unexpected report id {0:#04x}")]
+    UnexpectedReportId(u8),
+    #[error("Unexpected command {0:#04x}")]
+    UnexpectedCommand(u8),
+    #[error("Unexpected report length {0}")]
+    UnexpectedLength(usize),
+}
+
+/// In-memory model of the SinoWealth ISP bootloader.
+///
+/// `flash` holds the physical memory layout (firmware followed by the bootloader) exactly as it
+/// would be seen by a JTAG programmer. All the address redirects of the real bootloader are
+/// applied when serving feature reports.
+pub struct SimulatedBootloader {
+    flash: Vec<u8>,
+    firmware_size: usize,
+    read_addr: usize,
+    write_addr: usize,
+    /// The reset vector can only be redirected once after an erase
+    vector_writable: bool,
+    connected: bool,
+}
+
+impl SimulatedBootloader {
+    /// Creates a bootloader from a physical (JTAG) flash image. Images shorter than the flash are
+    /// padded with erased bytes.
+    pub fn with_flash(mut flash: Vec<u8>, firmware_size: usize) -> Self {
+        if flash.len() < firmware_size {
+            flash.resize(firmware_size, ERASED_BYTE);
+        }
+        Self {
+            flash,
+            firmware_size,
+            read_addr: 0,
+            write_addr: 0,
+            vector_writable: false,
+            connected: true,
+        }
+    }
+
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    /// Address of the LJMP instruction the bootloader jumps through to start the main firmware
+    fn ljmp_addr(&self) -> usize {
+        self.firmware_size - 5
+    }
+
+    pub fn set_feature_report(&mut self, data: &[u8]) -> Result<(), SimulatorError> {
+        if !self.connected {
+            return Err(SimulatorError::Disconnected);
+        }
+        match data.first() {
+            Some(&REPORT_ID_CMD) => self.handle_command(data),
+            Some(&REPORT_ID_XFER) => self.handle_write_page(data),
+            Some(&id) => Err(SimulatorError::UnexpectedReportId(id)),
+            None => Err(SimulatorError::UnexpectedLength(0)),
+        }
+    }
+
+    pub fn get_feature_report(&mut self, buf: &mut [u8]) -> Result<usize, SimulatorError> {
+        if !self.connected {
+            return Err(SimulatorError::Disconnected);
+        }
+        match buf.first() {
+            Some(&REPORT_ID_XFER) => self.handle_read_page(buf),
+            Some(&id) => Err(SimulatorError::UnexpectedReportId(id)),
+            None => Err(SimulatorError::UnexpectedLength(0)),
+        }
+    }
+
+    fn handle_command(&mut self, data: &[u8]) -> Result<(), SimulatorError> {
+        if data.len() != COMMAND_LENGTH {
+            return Err(SimulatorError::UnexpectedLength(data.len()));
+        }
+        let addr = data[2] as usize | (data[3] as usize) << 8;
+        match data[1] {
+            CMD_ENABLE_FIRMWARE => {
+                let ljmp_addr = self.ljmp_addr();
+                self.flash[ljmp_addr] = OPCODE_LJMP;
+            }
+            CMD_INIT_READ => self.read_addr = addr,
+            CMD_INIT_WRITE => self.write_addr = addr,
+            CMD_ERASE => {
+                self.flash[..self.firmware_size].fill(ERASED_BYTE);
+                // the reset vector is pointed at the bootloader so the device stays in ISP mode
+                self.flash[0] = OPCODE_LJMP;
+                self.flash[1..3].copy_from_slice(&(self.firmware_size as u16).to_be_bytes());
+                self.vector_writable = true;
+            }
+            CMD_REBOOT => self.connected = false,
+            cmd => return Err(SimulatorError::UnexpectedCommand(cmd)),
+        }
+        Ok(())
+    }
+
+    fn handle_write_page(&mut self, data: &[u8]) -> Result<(), SimulatorError> {
+        if data.len() < 2 {
+            return Err(SimulatorError::UnexpectedLength(data.len()));
+        }
+        if data[1] != XFER_WRITE_PAGE {
+            return Err(SimulatorError::UnexpectedCommand(data[1]));
+        }
+        let ljmp_addr = self.ljmp_addr();
+        for (i, b) in data[2..].iter().enumerate() {
+            let addr = self.write_addr + i;
+            match addr {
+                // the reset vector LJMP destination is redirected into the LJMP slot
+                1 | 2 if self.vector_writable => self.flash[ljmp_addr + addr] = *b,
+                0..=2 => {}
+                // the LJMP slot itself is only writable through the redirect above
+                addr if (ljmp_addr..ljmp_addr + 3).contains(&addr) => {}
+                // the bootloader section is write protected
+                addr if addr >= self.firmware_size => {}
+                addr => self.flash[addr] = *b,
+            }
+        }
+        if self.write_addr <= 2 && self.write_addr + data.len() - 2 > 2 {
+            self.vector_writable = false;
+        }
+        self.write_addr += data.len() - 2;
+        Ok(())
+    }
+
+    fn handle_read_page(&mut self, buf: &mut [u8]) -> Result<usize, SimulatorError> {
+        if buf.len() < 2 {
+            return Err(SimulatorError::UnexpectedLength(buf.len()));
+        }
+        let ljmp_addr = self.ljmp_addr();
+        buf[1] = XFER_READ_PAGE;
+        for i in 0..buf.len() - 2 {
+            let addr = self.read_addr + i;
+            buf[i + 2] = match addr {
+                // reads of the reset vector destination are served from the LJMP slot
+                1 | 2 => self.flash[ljmp_addr + addr],
+                // the LJMP slot itself is hidden
+                addr if (ljmp_addr..ljmp_addr + 3).contains(&addr) => ERASED_BYTE,
+                addr => self.flash.get(addr).copied().unwrap_or(ERASED_BYTE),
+            };
+        }
+        self.read_addr += buf.len() - 2;
+        Ok(buf.len())
+    }
+}
+
+#[test]
+fn test_simulated_erase() {
+    let mut bootloader = SimulatedBootloader::with_flash(vec![0xaa; 0x1000], 0x800);
+    bootloader
+        .set_feature_report(&[REPORT_ID_CMD, CMD_ERASE, 0, 0, 0, 0])
+        .unwrap();
+    assert_eq!(bootloader.flash()[0..3], [0x02, 0x08, 0x00]);
+    assert!(bootloader.flash()[3..0x800]
+        .iter()
+        .all(|b| *b == ERASED_BYTE));
+    assert!(bootloader.flash()[0x800..].iter().all(|b| *b == 0xaa));
+}
+
+#[test]
+fn test_simulated_reset_vector_redirect() {
+    let mut bootloader = SimulatedBootloader::with_flash(vec![], 0x800);
+    bootloader
+        .set_feature_report(&[REPORT_ID_CMD, CMD_ERASE, 0, 0, 0, 0])
+        .unwrap();
+    bootloader
+        .set_feature_report(&[REPORT_ID_CMD, CMD_INIT_WRITE, 0, 0, 0, 0])
+        .unwrap();
+    bootloader
+        .set_feature_report(&[REPORT_ID_XFER, XFER_WRITE_PAGE, 0x02, 0x01, 0x23, 0x45])
+        .unwrap();
+    bootloader
+        .set_feature_report(&[REPORT_ID_CMD, CMD_ENABLE_FIRMWARE, 0, 0, 0, 0])
+        .unwrap();
+
+    assert_eq!(bootloader.flash()[0..4], [0x02, 0x08, 0x00, 0x45]);
+    assert_eq!(bootloader.flash()[0x7fb..0x7fe], [0x02, 0x01, 0x23]);
+
+    bootloader
+        .set_feature_report(&[REPORT_ID_CMD, CMD_INIT_READ, 0, 0, 0, 0])
+        .unwrap();
+    let mut buf = [REPORT_ID_XFER, 0, 0, 0, 0, 0];
+    bootloader.get_feature_report(&mut buf).unwrap();
+    assert_eq!(
+        buf,
+        [REPORT_ID_XFER, XFER_READ_PAGE, 0x02, 0x01, 0x23, 0x45]
+    );
+}
@@ -0,0 +1,371 @@
+use std::{
+    cell::{Cell, RefCell},
+    fmt, fs,
+    io::{self, Write},
+    rc::Rc,
+    str::FromStr,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hidapi::HidError;
+use log::debug;
+use thiserror::Error;
+
+use crate::{to_hex_string, transport::ISPTransport};
+
+const TRACE_HEADER: &str = "# sinowealth-kb-tool trace v1";
+
+#[derive(Debug, Error)]
+pub enum TraceError {
+    #[error(transparent)]
+    IOError(#[from] io::Error),
+    #[error("Invalid trace entry on line {line}: {message}")]
+    InvalidEntry { line: usize, message: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Feature report sent to the device
+    Set,
+    /// Feature report received from the device
+    Get,
+}
+
+impl Direction {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            Direction::Set => "SET",
+            Direction::Get => "GET",
+        }
+    }
+}
+
+/// One feature report exchanged with the device
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    /// Seconds since the start of the session
+    pub timestamp: f64,
+    pub direction: Direction,
+    /// Report contents, for failed `GET` reports this is the request buffer
+    pub data: Vec<u8>,
+    pub error: Option<String>,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.6} {} {}",
+            self.timestamp,
+            self.direction.to_str(),
+            to_hex_string(&self.data)
+        )?;
+        if let Some(error) = &self.error {
+            write!(f, " ! {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TraceEntry {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (line, error) = match line.split_once(" ! ") {
+            Some((line, error)) => (line, Some(error.to_string())),
+            None => (line, None),
+        };
+        let mut tokens = line.split_whitespace();
+        let timestamp = tokens
+            .next()
+            .and_then(|t| t.parse::<f64>().ok())
+            .ok_or("missing timestamp")?;
+        let direction = match tokens.next() {
+            Some("SET") => Direction::Set,
+            Some("GET") => Direction::Get,
+            _ => return Err("missing direction".to_string()),
+        };
+        let data = tokens
+            .map(|t| u8::from_str_radix(t, 16).map_err(|_| format!("invalid byte `{}`", t)))
+            .collect::<Result<Vec<u8>, String>>()?;
+        Ok(TraceEntry {
+            timestamp,
+            direction,
+            data,
+            error,
+        })
+    }
+}
+
+pub fn parse_trace(trace: &str) -> Result<Vec<TraceEntry>, TraceError> {
+    trace
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            line.parse::<TraceEntry>()
+                .map_err(|message| TraceError::InvalidEntry {
+                    line: i + 1,
+                    message,
+                })
+        })
+        .collect()
+}
+
+/// Writes feature reports into a trace file, shared by everything that talks to the device
+pub struct TraceRecorder {
+    file: RefCell<fs::File>,
+    start: Instant,
+}
+
+impl TraceRecorder {
+    /// Creates a trace file and writes its header
+    pub fn create(path: &str) -> Result<Self, TraceError> {
+        let mut file = fs::File::create(path)?;
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        writeln!(file, "{}", TRACE_HEADER)?;
+        writeln!(file, "# started at {} (unix time)", started_at)?;
+        Ok(Self {
+            file: RefCell::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record<T>(&self, direction: Direction, data: &[u8], result: &Result<T, HidError>) {
+        let entry = TraceEntry {
+            timestamp: self.start.elapsed().as_secs_f64(),
+            direction,
+            data: data.to_vec(),
+            error: result.as_ref().err().map(|err| err.to_string()),
+        };
+        // a trace is diagnostic output, failing to write it must not affect the session
+        if let Err(err) = writeln!(self.file.borrow_mut(), "{}", entry) {
+            debug!("Failed to record trace entry: {}", err);
+        }
+    }
+}
+
+/// Transport wrapper that records every feature report into a trace file
+pub struct RecordingTransport {
+    inner: Box<dyn ISPTransport>,
+    recorder: Rc<TraceRecorder>,
+}
+
+impl RecordingTransport {
+    pub fn new(inner: Box<dyn ISPTransport>, recorder: Rc<TraceRecorder>) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl ISPTransport for RecordingTransport {
+    fn send_feature_report(&self, data: &[u8]) -> Result<(), HidError> {
+        let result = self.inner.send_feature_report(data);
+        self.recorder.record(Direction::Set, data, &result);
+        result
+    }
+
+    fn get_feature_report(&self, buf: &mut [u8]) -> Result<usize, HidError> {
+        let result = self.inner.get_feature_report(buf);
+        let size = *result.as_ref().unwrap_or(&buf.len());
+        self.recorder
+            .record(Direction::Get, &buf[..size.min(buf.len())], &result);
+        result
+    }
+
+    fn finish(&self) -> Result<(), HidError> {
+        self.inner.finish()
+    }
+}
+
+/// Transport that serves a recorded trace back instead of talking to a device
+pub struct ReplayTransport {
+    entries: Vec<TraceEntry>,
+    position: Cell<usize>,
+}
+
+impl ReplayTransport {
+    pub fn new(entries: Vec<TraceEntry>) -> Self {
+        Self {
+            entries,
+            position: Cell::new(0),
+        }
+    }
+
+    pub fn open(path: &str) -> Result<Self, TraceError> {
+        Ok(Self::new(parse_trace(&fs::read_to_string(path)?)?))
+    }
+
+    /// Skips leading `SET` entries of `data`, sent before the bootloader was connected, e.g. the
+    /// ISP mode switch recorded on a real device
+    pub fn skip_sent(self, data: &[u8]) -> Self {
+        let skipped = self
+            .entries
+            .iter()
+            .take_while(|entry| entry.direction == Direction::Set && entry.data == data)
+            .count();
+        self.position.set(skipped);
+        self
+    }
+
+    fn next_entry(&self, direction: Direction) -> Result<&TraceEntry, HidError> {
+        let position = self.position.get();
+        let entry = self
+            .entries
+            .get(position)
+            .ok_or_else(|| replay_error(format!("trace ended after {} entries", position)))?;
+        if entry.direction != direction {
+            return Err(replay_error(format!(
+                "expected {} at entry {}, got {}",
+                entry.direction.to_str(),
+                position + 1,
+                direction.to_str()
+            )));
+        }
+        self.position.set(position + 1);
+        Ok(entry)
+    }
+}
+
+fn replay_error(message: String) -> HidError {
+    HidError::HidApiError {
+        message: format!("Trace replay: {}", message),
+    }
+}
+
+impl ISPTransport for ReplayTransport {
+    fn send_feature_report(&self, data: &[u8]) -> Result<(), HidError> {
+        let entry = self.next_entry(Direction::Set)?;
+        if entry.data != data {
+            return Err(replay_error(format!(
+                "report mismatch at entry {}, expected [{}], got [{}]",
+                self.position.get(),
+                to_hex_string(&entry.data),
+                to_hex_string(data)
+            )));
+        }
+        match &entry.error {
+            Some(message) => Err(HidError::HidApiError {
+                message: message.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn get_feature_report(&self, buf: &mut [u8]) -> Result<usize, HidError> {
+        let entry = self.next_entry(Direction::Get)?;
+        if let Some(message) = &entry.error {
+            return Err(HidError::HidApiError {
+                message: message.clone(),
+            });
+        }
+        if entry.data.first() != buf.first() || entry.data.len() > buf.len() {
+            return Err(replay_error(format!(
+                "report mismatch at entry {}, expected report {:02X?} of {} bytes",
+                self.position.get(),
+                buf.first(),
+                buf.len()
+            )));
+        }
+        buf[..entry.data.len()].copy_from_slice(&entry.data);
+        Ok(entry.data.len())
+    }
+
+    fn finish(&self) -> Result<(), HidError> {
+        let position = self.position.get();
+        if position < self.entries.len() {
+            return Err(replay_error(format!(
+                "session ended after entry {}, {} more entries were recorded",
+                position,
+                self.entries.len() - position
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_trace_entry_roundtrip() {
+    let entry = TraceEntry {
+        timestamp: 1.5,
+        direction: Direction::Get,
+        data: vec![0x06, 0x72, 0xab],
+        error: None,
+    };
+    assert_eq!(entry.to_string(), "1.500000 GET 06 72 AB");
+    assert_eq!(entry.to_string().parse::<TraceEntry>(), Ok(entry));
+}
+
+#[test]
+fn test_trace_entry_with_error() {
+    let entry = "0.250000 SET 05 5A 00 00 00 00 ! hidapi error: device disconnected"
+        .parse::<TraceEntry>()
+        .unwrap();
+    assert_eq!(entry.direction, Direction::Set);
+    assert_eq!(entry.data, [0x05, 0x5a, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(
+        entry.error.as_deref(),
+        Some("hidapi error: device disconnected")
+    );
+}
+
+#[test]
+fn test_replay_transport() {
+    let trace = format!(
+        "{}\n0.000001 SET 05 52 00 00 00 00\n0.000002 GET 06 72 01 02\n",
+        TRACE_HEADER
+    );
+    let transport = ReplayTransport::new(parse_trace(&trace).unwrap());
+
+    transport
+        .send_feature_report(&[0x05, 0x52, 0x00, 0x00, 0x00, 0x00])
+        .unwrap();
+    let mut buf = [0x06, 0x00, 0x00, 0x00];
+    assert_eq!(transport.get_feature_report(&mut buf).unwrap(), 4);
+    assert_eq!(buf, [0x06, 0x72, 0x01, 0x02]);
+    assert!(transport.get_feature_report(&mut buf).is_err());
+}
+
+#[test]
+fn test_replay_transport_mismatch() {
+    let transport = ReplayTransport::new(parse_trace("0.000001 SET 05 52 00 00 00 00\n").unwrap());
+    assert!(transport
+        .send_feature_report(&[0x05, 0x45, 0x00, 0x00, 0x00, 0x00])
+        .is_err());
+}
+
+#[test]
+fn test_replay_transport_finish() {
+    let transport = ReplayTransport::new(
+        parse_trace("0.000001 SET 05 52 00 00 00 00\n0.000002 SET 05 5A 00 00 00 00\n").unwrap(),
+    );
+
+    transport
+        .send_feature_report(&[0x05, 0x52, 0x00, 0x00, 0x00, 0x00])
+        .unwrap();
+    assert_eq!(
+        transport.finish().unwrap_err().to_string(),
+        "hidapi error: Trace replay: session ended after entry 1, 1 more entries were recorded"
+    );
+
+    transport
+        .send_feature_report(&[0x05, 0x5a, 0x00, 0x00, 0x00, 0x00])
+        .unwrap();
+    assert!(transport.finish().is_ok());
+}
+
+#[test]
+fn test_replay_transport_skip_sent() {
+    let trace = "0.000001 SET 05 75 00 00 00 00 ! hidapi error: device disconnected\n\
+                 0.000002 SET 05 75 00 00 00 00\n\
+                 2.000000 SET 05 52 00 00 00 00\n";
+    let transport = ReplayTransport::new(parse_trace(trace).unwrap())
+        .skip_sent(&[0x05, 0x75, 0x00, 0x00, 0x00, 0x00]);
+
+    transport
+        .send_feature_report(&[0x05, 0x52, 0x00, 0x00, 0x00, 0x00])
+        .unwrap();
+    assert!(transport.finish().is_ok());
+}
@@ -0,0 +1,137 @@
+use std::{cell::RefCell, fs, io, path::PathBuf, rc::Rc};
+
+use hidapi::{HidDevice, HidError};
+use log::error;
+
+use crate::{
+    platform_spec::PlatformSpec,
+    simulator::{SimulatedBootloader, ERASED_BYTE},
+};
+
+#[cfg(target_os = "windows")]
+const REPORT_ID_XFER: u8 = 0x06;
+
+/// Feature report level access to an ISP bootloader
+pub trait ISPTransport {
+    fn send_feature_report(&self, data: &[u8]) -> Result<(), HidError>;
+    fn get_feature_report(&self, buf: &mut [u8]) -> Result<usize, HidError>;
+
+    /// Checks that the session ended as expected, e.g. that a replayed trace was used up
+    fn finish(&self) -> Result<(), HidError> {
+        Ok(())
+    }
+}
+
+/// Transport backed by real HID devices
+pub struct HidTransport {
+    cmd_device: HidDevice,
+    #[cfg(target_os = "windows")]
+    xfer_device: HidDevice,
+}
+
+impl HidTransport {
+    #[cfg(not(target_os = "windows"))]
+    pub fn new(device: HidDevice) -> Self {
+        Self { cmd_device: device }
+    }
+
+    #[cfg(target_os = "windows")]
+    pub fn new(cmd_device: HidDevice, xfer_device: HidDevice) -> Self {
+        Self {
+            cmd_device,
+            xfer_device,
+        }
+    }
+
+    /// Windows exposes each report collection as a separate device
+    fn device_for_report(&self, _report_id: u8) -> &HidDevice {
+        #[cfg(target_os = "windows")]
+        if _report_id == REPORT_ID_XFER {
+            return &self.xfer_device;
+        }
+        &self.cmd_device
+    }
+}
+
+impl ISPTransport for HidTransport {
+    fn send_feature_report(&self, data: &[u8]) -> Result<(), HidError> {
+        self.device_for_report(data[0]).send_feature_report(data)
+    }
+
+    fn get_feature_report(&self, buf: &mut [u8]) -> Result<usize, HidError> {
+        self.device_for_report(buf[0]).get_feature_report(buf)
+    }
+}
+
+/// Transport backed by an in-process simulated bootloader
+///
+/// When created from a file, the flash contents are written back to it once the transport is
+/// dropped, so consecutive runs operate on the same virtual device.
+#[derive(Clone)]
+pub struct SimulatedTransport {
+    bootloader: Rc<RefCell<SimulatedBootloader>>,
+    path: Option<PathBuf>,
+}
+
+impl SimulatedTransport {
+    pub fn new(bootloader: SimulatedBootloader) -> Self {
+        Self {
+            bootloader: Rc::new(RefCell::new(bootloader)),
+            path: None,
+        }
+    }
+
+    pub fn open(path: &str, platform: PlatformSpec) -> Result<Self, io::Error> {
+        let mut flash = fs::read(path)?;
+        flash.resize(
+            platform.firmware_size + platform.bootloader_size,
+            ERASED_BYTE,
+        );
+        let bootloader = SimulatedBootloader::with_flash(flash, platform.firmware_size);
+        let mut transport = Self::new(bootloader);
+        transport.path = Some(PathBuf::from(path));
+        Ok(transport)
+    }
+
+    pub fn flash(&self) -> Vec<u8> {
+        self.bootloader.borrow().flash().to_vec()
+    }
+}
+
+impl ISPTransport for SimulatedTransport {
+    fn send_feature_report(&self, data: &[u8]) -> Result<(), HidError> {
+        self.bootloader
+            .borrow_mut()
+            .set_feature_report(data)
+            .map_err(|err| HidError::HidApiError {
+                message: err.to_string(),
+            })
+    }
+
+    fn get_feature_report(&self, buf: &mut [u8]) -> Result<usize, HidError> {
+        self.bootloader
+            .borrow_mut()
+            .get_feature_report(buf)
+            .map_err(|err| HidError::HidApiError {
+                message: err.to_string(),
+            })
+    }
+}
+
+impl Drop for SimulatedTransport {
+    fn drop(&mut self) {
+        // only the last clone persists the flash contents
+        if Rc::strong_count(&self.bootloader) > 1 {
+            return;
+        }
+        if let Some(path) = &self.path {
+            if let Err(err) = fs::write(path, self.flash()) {
+                error!(
+                    "Failed to save simulated flash to {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,467 @@
+use std::{cmp::Reverse, collections::HashSet, fmt};
+
+use thiserror::Error;
+
+use crate::platform_spec::PLATFORMS;
+
+#[cfg(test)]
+use crate::platform_spec::{PLATFORM_SH68F881, PLATFORM_SH68F90};
+
+const PE_SIGNATURE: &[u8] = b"PE\0\0";
+const PE32_MAGIC: u16 = 0x10b;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+const RESOURCE_DIRECTORY_INDEX: usize = 2;
+const SECTION_HEADER_SIZE: usize = 40;
+/// Resource directories can nest arbitrarily, real files use three levels (type, name, language)
+const MAX_RESOURCE_DEPTH: usize = 8;
+/// Upper bound for the directory entries of all levels, far above what real updaters carry
+const MAX_RESOURCE_ENTRIES: usize = 0x4000;
+
+const LJMP: u8 = 0x02;
+const RETI: u8 = 0x32;
+/// Interrupt vectors following the reset vector on all supported platforms
+const INTERRUPT_VECTORS: [usize; 4] = [0x03, 0x0b, 0x13, 0x1b];
+
+#[derive(Debug, Error, PartialEq)]
+pub enum UpdaterError {
+    #[error("Not a PE executable")]
+    NotPE,
+    #[error("Executable is truncated")]
+    Truncated,
+    #[error("Resource directory is malformed")]
+    MalformedResources,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CandidateSource {
+    /// Resource identified by its type/name/language path
+    Resource(String),
+    Section(String),
+}
+
+impl fmt::Display for CandidateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CandidateSource::Resource(path) => write!(f, "resource {}", path),
+            CandidateSource::Section(name) => write!(f, "section {}", name),
+        }
+    }
+}
+
+/// Firmware image found in an updater executable
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub source: CandidateSource,
+    /// Offset of the image within its source
+    pub offset: usize,
+    pub platform: &'static str,
+    pub firmware: Vec<u8>,
+}
+
+/// State shared by all levels of a resource directory walk
+#[derive(Default)]
+struct ResourceWalk<'a> {
+    /// Offsets of the directories already walked, to reject directories referencing each other
+    visited: HashSet<usize>,
+    entries: usize,
+    resources: Vec<(String, &'a [u8])>,
+}
+
+struct Section {
+    name: String,
+    virtual_address: usize,
+    virtual_size: usize,
+    raw_offset: usize,
+    raw_size: usize,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, UpdaterError> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+        .ok_or(UpdaterError::Truncated)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, UpdaterError> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or(UpdaterError::Truncated)
+}
+
+/// Reads a length prefixed UTF-16 resource name
+fn resource_name(root: &[u8], offset: usize) -> Result<String, UpdaterError> {
+    let length = u16_at(root, offset)? as usize;
+    let chars = (0..length)
+        .map(|i| u16_at(root, offset + 2 + i * 2))
+        .collect::<Result<Vec<u16>, UpdaterError>>()?;
+    Ok(String::from_utf16_lossy(&chars))
+}
+
+struct PEFile<'a> {
+    data: &'a [u8],
+    sections: Vec<Section>,
+    resource_rva: usize,
+}
+
+impl<'a> PEFile<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, UpdaterError> {
+        if !data.starts_with(b"MZ") {
+            return Err(UpdaterError::NotPE);
+        }
+        let pe_offset = u32_at(data, 0x3c)? as usize;
+        if data.get(pe_offset..pe_offset + 4) != Some(PE_SIGNATURE) {
+            return Err(UpdaterError::NotPE);
+        }
+        let coff = pe_offset + 4;
+        let num_sections = u16_at(data, coff + 2)? as usize;
+        let optional_header_size = u16_at(data, coff + 16)? as usize;
+        let optional_header = coff + 20;
+
+        let data_directories = match u16_at(data, optional_header)? {
+            PE32_MAGIC => optional_header + 96,
+            PE32_PLUS_MAGIC => optional_header + 112,
+            _ => return Err(UpdaterError::NotPE),
+        };
+        let num_directories = u32_at(data, data_directories - 4)? as usize;
+        let resource_rva = if num_directories > RESOURCE_DIRECTORY_INDEX {
+            u32_at(data, data_directories + RESOURCE_DIRECTORY_INDEX * 8)? as usize
+        } else {
+            0
+        };
+
+        let section_table = optional_header + optional_header_size;
+        let sections = (0..num_sections)
+            .map(|i| {
+                let header = section_table + i * SECTION_HEADER_SIZE;
+                let name = data
+                    .get(header..header + 8)
+                    .ok_or(UpdaterError::Truncated)?;
+                Ok(Section {
+                    name: String::from_utf8_lossy(name)
+                        .trim_end_matches('\0')
+                        .to_string(),
+                    virtual_size: u32_at(data, header + 8)? as usize,
+                    virtual_address: u32_at(data, header + 12)? as usize,
+                    raw_size: u32_at(data, header + 16)? as usize,
+                    raw_offset: u32_at(data, header + 20)? as usize,
+                })
+            })
+            .collect::<Result<Vec<_>, UpdaterError>>()?;
+
+        Ok(Self {
+            data,
+            sections,
+            resource_rva,
+        })
+    }
+
+    fn section_data(&self, section: &Section) -> &'a [u8] {
+        let end = (section.raw_offset + section.raw_size).min(self.data.len());
+        self.data.get(section.raw_offset..end).unwrap_or_default()
+    }
+
+    /// Returns the file contents mapped at a relative virtual address
+    fn rva_data(&self, rva: usize, size: usize) -> Result<&'a [u8], UpdaterError> {
+        let section = self
+            .sections
+            .iter()
+            .find(|s| rva >= s.virtual_address && rva < s.virtual_address + s.virtual_size)
+            .ok_or(UpdaterError::Truncated)?;
+        let offset = section.raw_offset + rva - section.virtual_address;
+        self.data
+            .get(offset..offset + size)
+            .ok_or(UpdaterError::Truncated)
+    }
+
+    /// Collects the data of all resources along with their type/name/language paths
+    fn resources(&self) -> Result<Vec<(String, &'a [u8])>, UpdaterError> {
+        if self.resource_rva == 0 {
+            return Ok(vec![]);
+        }
+        let section = self
+            .sections
+            .iter()
+            .find(|s| s.virtual_address == self.resource_rva)
+            .ok_or(UpdaterError::Truncated)?;
+        let root = self.section_data(section);
+        let mut walk = ResourceWalk::default();
+        self.walk_resources(root, 0, String::new(), 0, &mut walk)?;
+        Ok(walk.resources)
+    }
+
+    fn walk_resources(
+        &self,
+        root: &'a [u8],
+        offset: usize,
+        path: String,
+        depth: usize,
+        walk: &mut ResourceWalk<'a>,
+    ) -> Result<(), UpdaterError> {
+        if depth > MAX_RESOURCE_DEPTH || !walk.visited.insert(offset) {
+            return Err(UpdaterError::MalformedResources);
+        }
+        let num_entries = u16_at(root, offset + 12)? as usize + u16_at(root, offset + 14)? as usize;
+        walk.entries += num_entries;
+        if walk.entries > MAX_RESOURCE_ENTRIES {
+            return Err(UpdaterError::MalformedResources);
+        }
+        for i in 0..num_entries {
+            let entry = offset + 16 + i * 8;
+            let name = u32_at(root, entry)?;
+            let target = u32_at(root, entry + 4)?;
+            let name = if name & 0x8000_0000 != 0 {
+                resource_name(root, (name & 0x7fff_ffff) as usize)?
+            } else {
+                name.to_string()
+            };
+            let path = if path.is_empty() {
+                name
+            } else {
+                format!("{}/{}", path, name)
+            };
+            if target & 0x8000_0000 != 0 {
+                let subdirectory = (target & 0x7fff_ffff) as usize;
+                self.walk_resources(root, subdirectory, path, depth + 1, walk)?;
+            } else {
+                let data_entry = target as usize;
+                let rva = u32_at(root, data_entry)? as usize;
+                let size = u32_at(root, data_entry + 4)? as usize;
+                walk.resources.push((path, self.rva_data(rva, size)?));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether the data looks like the start of 8051 firmware: a LJMP at the reset vector
+/// pointing into the image and plausible instructions at the interrupt vectors.
+fn is_plausible_firmware(image: &[u8]) -> bool {
+    if image[0] != LJMP {
+        return false;
+    }
+    let target = u16::from_be_bytes([image[1], image[2]]) as usize;
+    if target < INTERRUPT_VECTORS[0] || target >= image.len() {
+        return false;
+    }
+    INTERRUPT_VECTORS
+        .iter()
+        .all(|&addr| matches!(image[addr], LJMP | RETI | 0x00 | 0xff))
+}
+
+/// Looks for firmware images of all known platform sizes in a blob of data.
+///
+/// Only offsets aligned to 4 bytes are considered since compilers and resource compilers align
+/// embedded data at least that much. A blob with exactly the size of a platform's firmware is
+/// only matched against that platform. Smaller images starting at the same offset as a larger
+/// one are its truncated prefix, so only the largest platform that fits is reported.
+fn find_in_blob(source: CandidateSource, blob: &[u8], candidates: &mut Vec<Candidate>) {
+    let mut platforms = PLATFORMS
+        .entries()
+        .map(|(name, platform)| (*name, platform.firmware_size))
+        .collect::<Vec<_>>();
+    platforms.sort_by_key(|(name, size)| (Reverse(*size), *name));
+    if let Some(exact) = platforms.iter().find(|(_, size)| *size == blob.len()) {
+        platforms = vec![*exact];
+    }
+
+    for offset in (0..blob.len()).step_by(4) {
+        let found = platforms.iter().find_map(|(name, size)| {
+            blob.get(offset..offset + size)
+                .filter(|image| is_plausible_firmware(image))
+                .map(|image| (*name, image))
+        });
+        if let Some((name, image)) = found {
+            candidates.push(Candidate {
+                source: source.clone(),
+                offset,
+                platform: name,
+                firmware: image.to_vec(),
+            });
+        }
+    }
+}
+
+/// Finds firmware images embedded in the resources and sections of a PE executable
+pub fn find_firmware_candidates(executable: &[u8]) -> Result<Vec<Candidate>, UpdaterError> {
+    let pe = PEFile::parse(executable)?;
+    let mut candidates = vec![];
+
+    for (path, data) in pe.resources()? {
+        find_in_blob(CandidateSource::Resource(path), data, &mut candidates);
+    }
+    for section in &pe.sections {
+        // resources were already searched with better source information
+        if section.virtual_address == pe.resource_rva {
+            continue;
+        }
+        find_in_blob(
+            CandidateSource::Section(section.name.clone()),
+            pe.section_data(section),
+            &mut candidates,
+        );
+    }
+    Ok(candidates)
+}
+
+/// Builds a minimal PE32 executable with a single RCDATA resource and a `.data` section.
+///
+/// Without `resource_directory` the `.rsrc` section is left out of the data directories, as
+/// packers and stripped executables do.
+#[cfg(test)]
+fn build_pe(resource: &[u8], data: &[u8], resource_directory: bool) -> Vec<u8> {
+    const RSRC_RVA: u32 = 0x1000;
+    const DATA_RVA: u32 = 0x20000;
+    const HEADERS_SIZE: usize = 0x200;
+
+    // root (type 10) -> name 101 -> language 1033 -> data entry, each directory with one entry
+    let mut rsrc = vec![0u8; 0x60];
+    for (level, id) in [10u32, 101, 1033].iter().enumerate() {
+        let dir = level * 24;
+        rsrc[dir + 14..dir + 16].copy_from_slice(&1u16.to_le_bytes());
+        rsrc[dir + 16..dir + 20].copy_from_slice(&id.to_le_bytes());
+        let target = match level {
+            2 => 0x48,
+            _ => (dir as u32 + 24) | 0x8000_0000,
+        };
+        rsrc[dir + 20..dir + 24].copy_from_slice(&target.to_le_bytes());
+    }
+    rsrc[0x48..0x4c].copy_from_slice(&(RSRC_RVA + 0x60).to_le_bytes());
+    rsrc[0x4c..0x50].copy_from_slice(&(resource.len() as u32).to_le_bytes());
+    rsrc.extend_from_slice(resource);
+
+    let mut pe = vec![0u8; HEADERS_SIZE];
+    pe[0..2].copy_from_slice(b"MZ");
+    pe[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+    pe[0x40..0x44].copy_from_slice(PE_SIGNATURE);
+    let coff = 0x44;
+    pe[coff..coff + 2].copy_from_slice(&0x14cu16.to_le_bytes());
+    pe[coff + 2..coff + 4].copy_from_slice(&2u16.to_le_bytes());
+    pe[coff + 16..coff + 18].copy_from_slice(&224u16.to_le_bytes());
+    let optional_header = coff + 20;
+    pe[optional_header..optional_header + 2].copy_from_slice(&PE32_MAGIC.to_le_bytes());
+    pe[optional_header + 92..optional_header + 96].copy_from_slice(&16u32.to_le_bytes());
+    if resource_directory {
+        let directory = optional_header + 96 + RESOURCE_DIRECTORY_INDEX * 8;
+        pe[directory..directory + 4].copy_from_slice(&RSRC_RVA.to_le_bytes());
+    }
+
+    let sections: [(&[u8], u32, &[u8]); 2] =
+        [(b".rsrc", RSRC_RVA, &rsrc), (b".data", DATA_RVA, data)];
+    let mut raw_offset = HEADERS_SIZE;
+    for (i, (name, rva, contents)) in sections.iter().enumerate() {
+        let header = optional_header + 224 + i * SECTION_HEADER_SIZE;
+        pe[header..header + name.len()].copy_from_slice(name);
+        let size = (contents.len() as u32).to_le_bytes();
+        pe[header + 8..header + 12].copy_from_slice(&size);
+        pe[header + 12..header + 16].copy_from_slice(&rva.to_le_bytes());
+        pe[header + 16..header + 20].copy_from_slice(&size);
+        pe[header + 20..header + 24].copy_from_slice(&(raw_offset as u32).to_le_bytes());
+        raw_offset += contents.len();
+    }
+    for (_, _, contents) in sections {
+        pe.extend_from_slice(contents);
+    }
+    pe
+}
+
+#[cfg(test)]
+fn test_firmware(size: usize) -> Vec<u8> {
+    let mut firmware: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+    firmware[0..3].copy_from_slice(&[LJMP, 0x00, 0x66]);
+    for addr in INTERRUPT_VECTORS {
+        firmware[addr] = LJMP;
+    }
+    firmware
+}
+
+#[test]
+fn test_find_firmware_in_resource() {
+    let firmware = test_firmware(PLATFORM_SH68F90.firmware_size);
+    let candidates = find_firmware_candidates(&build_pe(&firmware, &[0xcc; 64], true)).unwrap();
+    assert_eq!(
+        candidates,
+        vec![Candidate {
+            source: CandidateSource::Resource("10/101/1033".to_string()),
+            offset: 0,
+            platform: "sh68f90",
+            firmware,
+        }]
+    );
+}
+
+#[test]
+fn test_find_firmware_in_section() {
+    let firmware = test_firmware(PLATFORM_SH68F881.firmware_size);
+    let mut data = vec![0xcc; 0x100];
+    data.extend_from_slice(&firmware);
+    data.extend_from_slice(&[0xcc; 0x100]);
+
+    let candidates = find_firmware_candidates(&build_pe(b"not firmware", &data, true)).unwrap();
+    assert_eq!(
+        candidates,
+        vec![Candidate {
+            source: CandidateSource::Section(".data".to_string()),
+            offset: 0x100,
+            platform: "sh68f881",
+            firmware,
+        }]
+    );
+}
+
+#[test]
+fn test_find_firmware_without_resource_directory() {
+    let firmware = test_firmware(PLATFORM_SH68F90.firmware_size);
+    let candidates = find_firmware_candidates(&build_pe(&firmware, &[0xcc; 64], false)).unwrap();
+    assert_eq!(
+        candidates,
+        vec![Candidate {
+            source: CandidateSource::Section(".rsrc".to_string()),
+            offset: 0x60,
+            platform: "sh68f90",
+            firmware,
+        }]
+    );
+}
+
+#[test]
+fn test_find_firmware_not_pe() {
+    assert_eq!(
+        find_firmware_candidates(&[0x02; 0x100]),
+        Err(UpdaterError::NotPE)
+    );
+}
+
+#[test]
+fn test_find_firmware_self_referencing_resources() {
+    let firmware = test_firmware(PLATFORM_SH68F90.firmware_size);
+    let rsrc = 0x200;
+
+    // the only entry of the root directory points back at the root
+    let mut pe = build_pe(&firmware, &[0xcc; 64], true);
+    pe[rsrc + 20..rsrc + 24].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+    assert_eq!(
+        find_firmware_candidates(&pe),
+        Err(UpdaterError::MalformedResources)
+    );
+
+    // two entries of a directory share the same subdirectory
+    let mut pe = build_pe(&firmware, &[0xcc; 64], true);
+    pe[rsrc + 20..rsrc + 24].copy_from_slice(&0x8000_0060u32.to_le_bytes());
+    let dir = rsrc + 0x60;
+    pe[dir + 12..dir + 16].copy_from_slice(&[0, 0, 2, 0]);
+    for entry in [dir + 16, dir + 24] {
+        pe[entry..entry + 4].copy_from_slice(&101u32.to_le_bytes());
+        pe[entry + 4..entry + 8].copy_from_slice(&0x8000_0018u32.to_le_bytes());
+    }
+    assert_eq!(
+        find_firmware_candidates(&pe),
+        Err(UpdaterError::MalformedResources)
+    );
+
+    // the entry count is rejected before any of the entries are read
+    let mut pe = build_pe(&firmware, &[0xcc; 64], true);
+    pe[rsrc + 14..rsrc + 16].copy_from_slice(&0xffffu16.to_le_bytes());
+    assert_eq!(
+        find_firmware_candidates(&pe),
+        Err(UpdaterError::MalformedResources)
+    );
+}
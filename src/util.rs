@@ -1,10 +1,17 @@
+use std::{fmt, ops::Range, str::FromStr, time::SystemTime};
+
 use crate::DeviceSpec;
+use clap_num::maybe_hex;
 use hidapi::HidError;
 use log::error;
 use thiserror::Error;
+use time::{macros::format_description, OffsetDateTime};
+
+#[cfg(test)]
+use std::time::{Duration, UNIX_EPOCH};
 
 #[cfg(test)]
-use crate::device_spec::DEVICE_BASE_SH68F90;
+use crate::device_spec::{DEVICE_BASE_SH68F881, DEVICE_BASE_SH68F90};
 
 #[derive(Debug, Clone, Error, PartialEq)]
 pub enum VerificationError {
@@ -39,35 +46,283 @@ pub fn verify(expected: &[u8], actual: &[u8]) -> Result<(), VerificationError> {
     Ok(())
 }
 
+/// Returns the address ranges in which the contents differ, including any excess length
+pub fn mismatch_ranges(expected: &[u8], actual: &[u8]) -> Vec<Range<usize>> {
+    let length = expected.len().max(actual.len());
+    let addrs: Vec<usize> = (0..length)
+        .filter(|addr| expected.get(*addr) != actual.get(*addr))
+        .collect();
+    address_ranges(&addrs)
+}
+
+/// Groups sorted addresses into contiguous ranges
+pub fn address_ranges(addrs: &[usize]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for &addr in addrs {
+        match ranges.last_mut() {
+            Some(range) if range.end == addr => range.end = addr + 1,
+            _ => ranges.push(addr..addr + 1),
+        }
+    }
+    ranges
+}
+
+/// Clears the reset vector copy at <firmware_size-4>.
+///
+/// `write_cycle` places the reset vector there for the bootloader, but it always reads back as
+/// zeroes, so this is how a written payload looks when read back from the device.
+pub fn clear_reset_vector_copy(firmware: &mut [u8], device_spec: DeviceSpec) {
+    let firmware_size = device_spec.platform.firmware_size;
+    firmware[firmware_size - 4..firmware_size - 2].fill(0);
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum AddressRangeParseError {
+    #[error("Invalid address range `{0}`, expected <START>-<END>, e.g. 0xe000-0xefff")]
+    InvalidSpec(String),
+}
+
+/// An inclusive address range, given on the command line as `START-END`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AddressRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl AddressRange {
+    pub fn to_range(self) -> Range<usize> {
+        self.start..self.end + 1
+    }
+}
+
+impl fmt::Display for AddressRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#06x}-{:#06x}", self.start, self.end)
+    }
+}
+
+impl FromStr for AddressRange {
+    type Err = AddressRangeParseError;
+
+    /// Parses specifications like `0xe000-0xefff` or `57344-61439`
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = || AddressRangeParseError::InvalidSpec(spec.to_string());
+        let (start, end) = spec.split_once('-').ok_or_else(invalid)?;
+        let start = maybe_hex::<usize>(start).map_err(|_| invalid())?;
+        let end = maybe_hex::<usize>(end).map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+        Ok(AddressRange { start, end })
+    }
+}
+
+/// Copies contents read from flash into a payload.
+///
+/// Returns whether the payload had data of its own in that range, which is replaced.
+pub fn merge_preserved(firmware: &mut [u8], range: Range<usize>, preserved: &[u8]) -> bool {
+    let overlaps = firmware[range.clone()].iter().any(|&b| b != 0);
+    firmware[range].copy_from_slice(preserved);
+    overlaps
+}
+
+#[derive(Debug, Clone, Error, PartialEq)]
+pub enum PayloadValidationError {
+    #[error("Payload is {size} bytes, larger than the firmware section ({firmware_size} bytes)")]
+    TooLarge { size: usize, firmware_size: usize },
+    #[error("Payload does not start with an LJMP, found {value:#04x}. The bootloader relies on it to start the firmware")]
+    MissingResetJump { value: u8 },
+    #[error("Reset vector jumps to {target:#06x}, outside of the firmware section or into the LJMP slot the bootloader jumps through")]
+    InvalidResetTarget { target: u16 },
+    #[error("Payload has data at {addr:#06x}-{end:#06x}, which is overwritten with the reset vector address", end = addr + 1)]
+    ResetVectorSlotInUse { addr: usize },
+}
+
+/// Checks a payload for things `write_cycle` would silently corrupt or fail on late
+pub fn validate_payload(firmware: &[u8], device_spec: DeviceSpec) -> Vec<PayloadValidationError> {
+    let firmware_size = device_spec.platform.firmware_size;
+    let mut errors = vec![];
+
+    if firmware.len() > firmware_size {
+        errors.push(PayloadValidationError::TooLarge {
+            size: firmware.len(),
+            firmware_size,
+        });
+    }
+
+    match Jump::decode(firmware, 0) {
+        Some(Jump::LJMP(target)) => {
+            if check_firmware_target(0x0001, target, device_spec).is_err() {
+                errors.push(PayloadValidationError::InvalidResetTarget { target });
+            }
+        }
+        _ => errors.push(PayloadValidationError::MissingResetJump {
+            value: firmware.first().copied().unwrap_or_default(),
+        }),
+    }
+
+    // the slot may already hold the copy of the reset vector address, e.g. in a flash dump
+    let slot = firmware_size - 4..firmware_size - 2;
+    if let (Some(vector), Some(contents)) = (firmware.get(1..3), firmware.get(slot.clone())) {
+        if contents != [0, 0] && contents != vector {
+            errors.push(PayloadValidationError::ResetVectorSlotInUse { addr: slot.start });
+        }
+    }
+
+    errors
+}
+
+const OPCODE_LJMP: u8 = 0x02;
+const OPCODE_SJMP: u8 = 0x80;
+/// AJMP is encoded as `aaa00001`, with the upper 3 bits holding address bits 8-10
+const OPCODE_AJMP_MASK: u8 = 0x1f;
+const OPCODE_AJMP: u8 = 0x01;
+
+/// An unconditional 8051 jump, as found at the reset vector
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Jump {
+    LJMP(u16),
+    AJMP(u16),
+    SJMP(u16),
+}
+
+impl Jump {
+    /// Decodes the jump instruction at `addr`, resolving the target of relative encodings
+    pub fn decode(input: &[u8], addr: usize) -> Option<Self> {
+        let opcode = *input.get(addr)?;
+        let operand = *input.get(addr + 1)?;
+        if opcode == OPCODE_LJMP {
+            let low = *input.get(addr + 2)?;
+            return Some(Jump::LJMP(u16::from_be_bytes([operand, low])));
+        }
+
+        // AJMP and SJMP are relative to the address of the next instruction
+        let next = addr as u16 + 2;
+        if opcode & OPCODE_AJMP_MASK == OPCODE_AJMP {
+            let target = (next & 0xf800) | ((opcode as u16 & 0xe0) << 3) | operand as u16;
+            return Some(Jump::AJMP(target));
+        }
+        if opcode == OPCODE_SJMP {
+            let target = next.checked_add_signed(operand as i8 as i16)?;
+            return Some(Jump::SJMP(target));
+        }
+        None
+    }
+
+    pub fn target(self) -> u16 {
+        match self {
+            Jump::LJMP(target) | Jump::AJMP(target) | Jump::SJMP(target) => target,
+        }
+    }
+}
+
+impl fmt::Display for Jump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Jump::LJMP(target) => write!(f, "LJMP {:#06x}", target),
+            Jump::AJMP(target) => write!(f, "AJMP {:#06x}", target),
+            Jump::SJMP(target) => write!(f, "SJMP {:#06x}", target),
+        }
+    }
+}
+
+/// Rewrites an AJMP or SJMP reset vector as the equivalent LJMP, which the bootloader relies on.
+///
+/// Returns the jump found at 0x0000. The two byte encodings leave 0x0002 unused, since the next
+/// interrupt vector starts at 0x0003, so the longer LJMP fits in their place.
+pub fn normalize_reset_vector(input: &mut [u8]) -> Result<Jump, PayloadConversionError> {
+    let jump =
+        Jump::decode(input, 0).ok_or(PayloadConversionError::LJMPNotFoundError { addr: 0x0000 })?;
+    input[0] = OPCODE_LJMP;
+    input[1..3].copy_from_slice(&jump.target().to_be_bytes());
+    Ok(jump)
+}
+
 #[derive(Debug, Error)]
 pub enum PayloadConversionError {
     #[error("Expected LJMP not found at {addr:#06x}")]
     LJMPNotFoundError { addr: u16 },
     #[error("Unexpected addr at {source_addr:#06x} pointing to {target_addr:#06x}")]
     UnexpectedAddressError { source_addr: u16, target_addr: u16 },
+    #[error("Unable to detect whether the payload is laid out for ISP or JTAG")]
+    UnknownLayoutError,
+    #[error("Unexpected {section} size {actual}, expected {expected} bytes")]
+    UnexpectedSizeError {
+        section: &'static str,
+        expected: usize,
+        actual: usize,
+    },
 }
 
-pub fn convert_to_jtag_payload(
-    input: &mut [u8],
-    device_spec: DeviceSpec,
-) -> Result<(), PayloadConversionError> {
-    if input[0] != 0x02 {
-        return Err(PayloadConversionError::LJMPNotFoundError { addr: 0x0000 });
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadLayout {
+    /// The firmware section as written through the bootloader, starting with its own reset vector
+    ISP,
+    /// The physical flash contents, with the reset vector jumping into the bootloader
+    JTAG,
+}
+
+impl fmt::Display for PayloadLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PayloadLayout::ISP => write!(f, "ISP"),
+            PayloadLayout::JTAG => write!(f, "JTAG"),
+        }
     }
+}
 
-    let main_fw_address = u16::from_be_bytes(input[1..3].try_into().unwrap());
-    if main_fw_address > 0xefff {
+/// Detects the layout of a payload from its reset vector and the LJMP slot at <firmware_size-5>.
+///
+/// A JTAG image jumps into the bootloader at 0x0000 and keeps the firmware's own LJMP in the
+/// slot. Returns `None` for images larger than flash or with only one of the two jumps.
+pub fn detect_layout(input: &[u8], device_spec: DeviceSpec) -> Option<PayloadLayout> {
+    if input.len() > device_spec.total_flash_size() {
+        return None;
+    }
+    let firmware_size = device_spec.platform.firmware_size;
+    let bootloader_jump = matches!(
+        Jump::decode(input, 0),
+        Some(Jump::LJMP(target)) if target as usize == firmware_size
+    );
+    let slot_jump = matches!(Jump::decode(input, firmware_size - 5), Some(Jump::LJMP(_)));
+    match (bootloader_jump, slot_jump) {
+        (true, true) => Some(PayloadLayout::JTAG),
+        (true, false) => None,
+        (false, _) => Some(PayloadLayout::ISP),
+    }
+}
+
+/// Checks that a jump to the main firmware lands in the firmware section, outside of the LJMP
+/// slot at <firmware_size-5> the bootloader jumps through
+fn check_firmware_target(
+    source_addr: usize,
+    target_addr: u16,
+    device_spec: DeviceSpec,
+) -> Result<(), PayloadConversionError> {
+    let firmware_size = device_spec.platform.firmware_size;
+    let ljmp_slot = firmware_size - 5..firmware_size - 2;
+    let target = target_addr as usize;
+    if target >= firmware_size || ljmp_slot.contains(&target) {
         return Err(PayloadConversionError::UnexpectedAddressError {
-            source_addr: 0x0001,
-            target_addr: main_fw_address,
+            source_addr: source_addr as u16,
+            target_addr,
         });
     }
+    Ok(())
+}
+
+pub fn convert_to_jtag_payload(
+    input: &mut [u8],
+    device_spec: DeviceSpec,
+) -> Result<(), PayloadConversionError> {
+    let main_fw_address = normalize_reset_vector(input)?.target();
+    check_firmware_target(0x0001, main_fw_address, device_spec)?;
 
     let bootloader_ljmp_addr = (device_spec.platform.firmware_size as u16).to_be_bytes();
     let ljmp_addr = device_spec.platform.firmware_size - 5;
 
     input[1..3].copy_from_slice(&bootloader_ljmp_addr);
-    input[ljmp_addr] = 0x02;
+    input[ljmp_addr] = OPCODE_LJMP;
     input[ljmp_addr + 1..ljmp_addr + 3].copy_from_slice(&main_fw_address.to_be_bytes());
 
     Ok(())
@@ -77,23 +332,18 @@ pub fn convert_to_isp_payload(
     input: &mut [u8],
     device_spec: DeviceSpec,
 ) -> Result<(), PayloadConversionError> {
-    if input[0] != 0x02 {
+    if input[0] != OPCODE_LJMP {
         return Err(PayloadConversionError::LJMPNotFoundError { addr: 0 });
     }
 
     let ljmp_addr = device_spec.platform.firmware_size - 5;
-    if input[ljmp_addr] != 0x02 {
-        return Err(PayloadConversionError::LJMPNotFoundError { addr: 0x0000 });
-    }
-
-    let main_fw_address =
-        u16::from_be_bytes(input[ljmp_addr + 1..ljmp_addr + 3].try_into().unwrap());
-    if main_fw_address > 0xefff {
-        return Err(PayloadConversionError::UnexpectedAddressError {
-            source_addr: (ljmp_addr + 1) as u16,
-            target_addr: main_fw_address,
+    // the bootloader always jumps through the slot as a 3 byte LJMP
+    let Some(Jump::LJMP(main_fw_address)) = Jump::decode(input, ljmp_addr) else {
+        return Err(PayloadConversionError::LJMPNotFoundError {
+            addr: ljmp_addr as u16,
         });
-    }
+    };
+    check_firmware_target(ljmp_addr + 1, main_fw_address, device_spec)?;
 
     input[1..3].copy_from_slice(&main_fw_address.to_be_bytes());
     input[ljmp_addr..ljmp_addr + 3].fill(0x00);
@@ -101,6 +351,69 @@ pub fn convert_to_isp_payload(
     Ok(())
 }
 
+/// Cuts a full flash image into the firmware and bootloader sections
+pub fn split_flash(
+    input: &[u8],
+    device_spec: DeviceSpec,
+) -> Result<(Vec<u8>, Vec<u8>), PayloadConversionError> {
+    let total_flash_size = device_spec.total_flash_size();
+    if input.len() != total_flash_size {
+        return Err(PayloadConversionError::UnexpectedSizeError {
+            section: "flash image",
+            expected: total_flash_size,
+            actual: input.len(),
+        });
+    }
+    let (firmware, bootloader) = input.split_at(device_spec.platform.firmware_size);
+    Ok((firmware.to_vec(), bootloader.to_vec()))
+}
+
+/// Builds a full flash image for an external programmer from an ISP or JTAG layout firmware
+/// and a bootloader.
+///
+/// Returns the layout the firmware was in, an ISP layout firmware is converted to JTAG.
+pub fn merge_flash(
+    firmware: &[u8],
+    bootloader: &[u8],
+    device_spec: DeviceSpec,
+) -> Result<(Vec<u8>, PayloadLayout), PayloadConversionError> {
+    let firmware_size = device_spec.platform.firmware_size;
+    let bootloader_size = device_spec.platform.bootloader_size;
+    if firmware.len() > firmware_size {
+        return Err(PayloadConversionError::UnexpectedSizeError {
+            section: "firmware",
+            expected: firmware_size,
+            actual: firmware.len(),
+        });
+    }
+    if bootloader.len() != bootloader_size {
+        return Err(PayloadConversionError::UnexpectedSizeError {
+            section: "bootloader",
+            expected: bootloader_size,
+            actual: bootloader.len(),
+        });
+    }
+
+    let mut image = firmware.to_vec();
+    image.resize(firmware_size, 0);
+    let layout =
+        detect_layout(&image, device_spec).ok_or(PayloadConversionError::UnknownLayoutError)?;
+    if layout == PayloadLayout::ISP {
+        convert_to_jtag_payload(&mut image, device_spec)?;
+    }
+    image.extend_from_slice(bootloader);
+    Ok((image, layout))
+}
+
+/// Formats a time as a compact UTC timestamp, e.g. `20240131T235959Z`
+pub fn utc_timestamp(time: SystemTime) -> String {
+    OffsetDateTime::from(time)
+        .format(format_description!(
+            "[year][month][day]T[hour][minute][second]Z"
+        ))
+        .unwrap()
+}
+
 pub fn to_hex_string(bytes: &[u8]) -> String {
     let strs: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
     strs.join(" ")
@@ -118,6 +431,19 @@ pub fn is_expected_error(err: &HidError) -> bool {
     }
 }
 
+#[cfg(test)]
+pub const TEST_DEVICE_SPECS: [DeviceSpec; 2] = [DEVICE_BASE_SH68F90, DEVICE_BASE_SH68F881];
+
+/// An ISP layout payload with a reset vector jumping to 0x0066 and an empty LJMP slot
+#[cfg(test)]
+pub fn test_payload(device_spec: DeviceSpec) -> Vec<u8> {
+    let firmware_size = device_spec.platform.firmware_size;
+    let mut firmware: Vec<u8> = (0..firmware_size).map(|i| (i % 251) as u8).collect();
+    firmware[0..3].copy_from_slice(&[0x02, 0x00, 0x66]);
+    firmware[firmware_size - 5..].fill(0x00);
+    firmware
+}
+
 #[test]
 fn test_verify_success() {
     assert!(verify(&vec![1, 2, 3, 4], &vec![1, 2, 3, 4]).is_ok());
@@ -146,6 +472,106 @@ fn test_verify_error_byte_mismatch() {
     );
 }
 
+#[test]
+fn test_mismatch_ranges() {
+    assert_eq!(mismatch_ranges(&[1, 2, 3, 4], &[1, 2, 3, 4]), vec![]);
+    assert_eq!(
+        mismatch_ranges(&[1, 2, 3, 4, 5, 6], &[0, 2, 0, 0, 5]),
+        vec![0..1, 2..4, 5..6]
+    );
+}
+
+#[test]
+fn test_clear_reset_vector_copy() {
+    let device_spec = DEVICE_BASE_SH68F90;
+    let mut firmware = vec![0xaa; device_spec.platform.firmware_size];
+
+    clear_reset_vector_copy(&mut firmware, device_spec);
+
+    assert_eq!(firmware[0xeffb..0xf000], [0xaa, 0x00, 0x00, 0xaa, 0xaa]);
+}
+
+#[test]
+fn test_address_range_from_str() {
+    assert_eq!(
+        "0xe000-0xefff".parse(),
+        Ok(AddressRange {
+            start: 0xe000,
+            end: 0xefff
+        })
+    );
+    assert_eq!("16-16".parse::<AddressRange>().unwrap().to_range(), 16..17);
+    assert!("0xe000".parse::<AddressRange>().is_err());
+    assert!("0xefff-0xe000".parse::<AddressRange>().is_err());
+    assert!("0xe000-end".parse::<AddressRange>().is_err());
+}
+
+#[test]
+fn test_merge_preserved() {
+    let mut firmware = vec![0x00, 0x00, 0x12, 0x00];
+    assert!(!merge_preserved(&mut firmware, 0..2, &[0xaa, 0xbb]));
+    assert_eq!(firmware, [0xaa, 0xbb, 0x12, 0x00]);
+    assert!(merge_preserved(&mut firmware, 2..4, &[0xcc, 0xdd]));
+    assert_eq!(firmware, [0xaa, 0xbb, 0xcc, 0xdd]);
+}
+
+#[test]
+fn test_validate_payload() {
+    let device_spec = DEVICE_BASE_SH68F90;
+    let mut firmware = test_payload(device_spec);
+    assert_eq!(validate_payload(&firmware, device_spec), vec![]);
+
+    // a copy of the reset vector address is what the device holds after a write
+    firmware[0xeffc..0xeffe].copy_from_slice(&[0x00, 0x66]);
+    assert_eq!(validate_payload(&firmware, device_spec), vec![]);
+
+    firmware[0xeffc] = 0x12;
+    firmware[0] = 0x00;
+    firmware.push(0);
+    assert_eq!(
+        validate_payload(&firmware, device_spec),
+        vec![
+            PayloadValidationError::TooLarge {
+                size: 0xf001,
+                firmware_size: 0xf000
+            },
+            PayloadValidationError::MissingResetJump { value: 0x00 },
+            PayloadValidationError::ResetVectorSlotInUse { addr: 0xeffc },
+        ]
+    );
+}
+
+#[test]
+fn test_validate_payload_reset_target() {
+    let device_spec = DEVICE_BASE_SH68F90;
+    let mut firmware = test_payload(device_spec);
+
+    // into the bootloader, which would then jump to itself
+    firmware[0..3].copy_from_slice(&[0x02, 0xf0, 0x00]);
+    assert_eq!(
+        validate_payload(&firmware, device_spec),
+        vec![PayloadValidationError::InvalidResetTarget { target: 0xf000 }]
+    );
+
+    firmware[0..3].copy_from_slice(&[0x02, 0xef, 0xfb]);
+    assert_eq!(
+        validate_payload(&firmware, device_spec),
+        vec![PayloadValidationError::InvalidResetTarget { target: 0xeffb }]
+    );
+
+    firmware[0..3].copy_from_slice(&[0x02, 0xef, 0xfa]);
+    assert_eq!(validate_payload(&firmware, device_spec), vec![]);
+}
+
+#[test]
+fn test_utc_timestamp() {
+    assert_eq!(utc_timestamp(UNIX_EPOCH), "19700101T000000Z");
+    assert_eq!(
+        utc_timestamp(UNIX_EPOCH + Duration::from_secs(1709251199)),
+        "20240229T235959Z"
+    );
+}
+
 #[test]
 fn test_convert_to_jtag_payload() {
     let device_spec = DEVICE_BASE_SH68F90;
@@ -160,6 +586,170 @@ fn test_convert_to_jtag_payload() {
     assert_eq!(firmware[0xeffb..0xeffe], [0x02, 0x00, 0x66]);
 }
 
+#[test]
+fn test_jump_decode() {
+    assert_eq!(
+        Jump::decode(&[0x02, 0x12, 0x34], 0),
+        Some(Jump::LJMP(0x1234))
+    );
+    // AJMP with address bits 8-10 set to 0b101
+    assert_eq!(Jump::decode(&[0xa1, 0x66], 0), Some(Jump::AJMP(0x0566)));
+    assert_eq!(Jump::decode(&[0x80, 0x2e], 0), Some(Jump::SJMP(0x0030)));
+    // a backwards SJMP from 0x0000 has nowhere to go
+    assert_eq!(Jump::decode(&[0x80, 0xf0], 0), None);
+    assert_eq!(Jump::decode(&[0x75, 0x81, 0x30], 0), None);
+    assert_eq!(Jump::decode(&[0x02, 0x12], 0), None);
+
+    let mut input = vec![0; 0xf000];
+    input[0xeffb..0xeffd].copy_from_slice(&[0x80, 0x03]);
+    assert_eq!(Jump::decode(&input, 0xeffb), Some(Jump::SJMP(0xf000)));
+    input[0xeffb..0xeffd].copy_from_slice(&[0x41, 0x66]);
+    assert_eq!(Jump::decode(&input, 0xeffb), Some(Jump::AJMP(0xea66)));
+}
+
+#[test]
+fn test_normalize_reset_vector() {
+    let mut input = [0x21, 0x30, 0xff, 0x32];
+    assert_eq!(
+        normalize_reset_vector(&mut input).unwrap(),
+        Jump::AJMP(0x0130)
+    );
+    assert_eq!(input, [0x02, 0x01, 0x30, 0x32]);
+
+    let mut input = [0x80, 0x10, 0x00, 0x32];
+    assert_eq!(
+        normalize_reset_vector(&mut input).unwrap(),
+        Jump::SJMP(0x0012)
+    );
+    assert_eq!(input, [0x02, 0x00, 0x12, 0x32]);
+
+    assert!(normalize_reset_vector(&mut [0x00, 0x00, 0x00]).is_err());
+}
+
+#[test]
+fn test_convert_to_jtag_payload_sjmp() {
+    let device_spec = DEVICE_BASE_SH68F90;
+    let mut firmware: [u8; 65536] = [0; 65536];
+    firmware[0] = 0x80;
+    firmware[1] = 0x7e;
+
+    convert_to_jtag_payload(&mut firmware, device_spec).unwrap();
+
+    assert_eq!(firmware[0..3], [0x02, 0xf0, 0x00]);
+    assert_eq!(firmware[0xeffb..0xeffe], [0x02, 0x00, 0x80]);
+}
+
+#[test]
+fn test_convert_to_jtag_payload_bounds() {
+    for device_spec in TEST_DEVICE_SPECS {
+        let firmware_size = device_spec.platform.firmware_size;
+        let convert = |target: usize| {
+            let mut firmware = test_payload(device_spec);
+            firmware[1..3].copy_from_slice(&(target as u16).to_be_bytes());
+            convert_to_jtag_payload(&mut firmware, device_spec)
+        };
+
+        assert!(convert(0x0066).is_ok());
+        assert!(convert(firmware_size - 6).is_ok());
+        assert!(convert(firmware_size - 2).is_ok());
+        // the LJMP slot and the bootloader
+        for target in [firmware_size - 5, firmware_size - 3, firmware_size, 0xf000] {
+            assert!(matches!(
+                convert(target),
+                Err(PayloadConversionError::UnexpectedAddressError {
+                    source_addr: 0x0001,
+                    ..
+                })
+            ));
+        }
+    }
+}
+
+#[test]
+fn test_convert_to_isp_payload_bounds() {
+    for device_spec in TEST_DEVICE_SPECS {
+        let firmware_size = device_spec.platform.firmware_size;
+        let ljmp_addr = firmware_size - 5;
+        let convert = |target: usize| {
+            let mut firmware = test_payload(device_spec);
+            firmware[ljmp_addr] = 0x02;
+            firmware[ljmp_addr + 1..ljmp_addr + 3].copy_from_slice(&(target as u16).to_be_bytes());
+            convert_to_isp_payload(&mut firmware, device_spec)
+        };
+
+        assert!(convert(0x0066).is_ok());
+        for target in [ljmp_addr, firmware_size, firmware_size + 0x800] {
+            assert!(matches!(
+                convert(target),
+                Err(PayloadConversionError::UnexpectedAddressError { source_addr, .. })
+                    if source_addr as usize == ljmp_addr + 1
+            ));
+        }
+    }
+}
+
+#[test]
+fn test_detect_layout() {
+    for device_spec in TEST_DEVICE_SPECS {
+        let firmware_size = device_spec.platform.firmware_size;
+        let mut firmware = test_payload(device_spec);
+        assert_eq!(
+            detect_layout(&firmware, device_spec),
+            Some(PayloadLayout::ISP)
+        );
+
+        convert_to_jtag_payload(&mut firmware, device_spec).unwrap();
+        assert_eq!(
+            detect_layout(&firmware, device_spec),
+            Some(PayloadLayout::JTAG)
+        );
+        firmware.resize(device_spec.total_flash_size(), 0xff);
+        assert_eq!(
+            detect_layout(&firmware, device_spec),
+            Some(PayloadLayout::JTAG)
+        );
+
+        firmware[firmware_size - 5] = 0x00;
+        assert_eq!(detect_layout(&firmware, device_spec), None);
+        firmware.push(0);
+        assert_eq!(detect_layout(&firmware, device_spec), None);
+    }
+}
+
+#[test]
+fn test_split_and_merge_flash() {
+    for device_spec in TEST_DEVICE_SPECS {
+        let firmware_size = device_spec.platform.firmware_size;
+        let firmware = test_payload(device_spec);
+        let bootloader = vec![0xaa; device_spec.platform.bootloader_size];
+
+        let (image, layout) = merge_flash(&firmware, &bootloader, device_spec).unwrap();
+        assert_eq!(layout, PayloadLayout::ISP);
+        assert_eq!(image.len(), device_spec.total_flash_size());
+        assert_eq!(
+            detect_layout(&image, device_spec),
+            Some(PayloadLayout::JTAG)
+        );
+        assert_eq!(
+            image[firmware_size - 5..firmware_size - 2],
+            [0x02, 0x00, 0x66]
+        );
+
+        let (jtag_firmware, split_bootloader) = split_flash(&image, device_spec).unwrap();
+        assert_eq!(split_bootloader, bootloader);
+        assert_eq!(jtag_firmware, image[..firmware_size]);
+
+        // a JTAG layout firmware is taken as is
+        let (merged, layout) = merge_flash(&jtag_firmware, &bootloader, device_spec).unwrap();
+        assert_eq!(layout, PayloadLayout::JTAG);
+        assert_eq!(merged, image);
+
+        assert!(split_flash(&image[1..], device_spec).is_err());
+        assert!(merge_flash(&firmware, &bootloader[1..], device_spec).is_err());
+        assert!(merge_flash(&image, &bootloader, device_spec).is_err());
+    }
+}
+
 #[test]
 fn test_convert_to_isp_payload() {
     let device_spec = DEVICE_BASE_SH68F90;
@@ -176,3 +766,19 @@ fn test_convert_to_isp_payload() {
     assert_eq!(firmware[0..3], [0x02, 0x00, 0x66]);
     assert_eq!(firmware[0xeffb..0xeffe], [0x00, 0x00, 0x00]);
 }
+
+#[test]
+fn test_convert_to_isp_payload_slot_not_ljmp() {
+    let device_spec = DEVICE_BASE_SH68F90;
+    // an AJMP, an SJMP or plain data in the slot is not an entry point
+    for slot in [[0x21, 0x66, 0x00], [0x41, 0x00, 0x66], [0x80, 0x10, 0x00]] {
+        let mut firmware = test_payload(device_spec);
+        firmware[1..3].copy_from_slice(&[0xf0, 0x00]);
+        firmware[0xeffb..0xeffe].copy_from_slice(&slot);
+
+        assert!(matches!(
+            convert_to_isp_payload(&mut firmware, device_spec),
+            Err(PayloadConversionError::LJMPNotFoundError { addr: 0xeffb })
+        ));
+    }
+}
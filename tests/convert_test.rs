@@ -76,3 +76,147 @@ fn test_convert_to_jtag_bin() {
         "df1ff7b247ae12dda37aa69730f090af"
     );
 }
+
+#[test]
+#[serial]
+fn test_convert_auto() {
+    let output_file = test_filename!("hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("convert")
+        .args(&["--device", "nuphy-air60"])
+        .args(&["--direction", "auto"])
+        .arg(get_fixture_path("nuphy-air60_smk.hex"))
+        .arg(&output_file)
+        .assert();
+
+    assert.success().stderr(predicates::str::contains(
+        "Detected ISP layout, converting to_jtag",
+    ));
+
+    let computed_md5 = md5::compute(fs::read(&output_file).unwrap());
+    assert_eq!(
+        format!("{:x}", computed_md5),
+        "3bbd99f81678fc11fdf1ba9eaaac2bd1"
+    );
+
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("convert")
+        .args(&["--device", "nuphy-air60"])
+        .args(&["--direction", "auto"])
+        .arg(get_fixture_path("nuphy-air60_smk_jtag.hex"))
+        .arg(&output_file)
+        .assert();
+
+    assert.success().stderr(predicates::str::contains(
+        "Detected JTAG layout, converting to_isp",
+    ));
+
+    let computed_md5 = md5::compute(fs::read(&output_file).unwrap());
+    assert_eq!(
+        format!("{:x}", computed_md5),
+        "6594e5a1ab671deb40f36483a84ad61f"
+    );
+}
+
+#[test]
+#[serial]
+fn test_merge_and_split() {
+    let bootloader_file = test_filename!("bootloader.bin");
+    fs::write(&bootloader_file, vec![0x5a; 4096]).unwrap();
+
+    let full_file = test_filename!("full.bin");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("merge")
+        .args(&["--device", "nuphy-air60"])
+        .arg(get_fixture_path("nuphy-air60_smk.hex"))
+        .arg(&bootloader_file)
+        .arg(&full_file)
+        .assert();
+
+    assert.success().stderr(predicates::str::contains(
+        "Firmware has the ISP layout, converted it to the JTAG layout",
+    ));
+
+    let full = fs::read(&full_file).unwrap();
+    assert_eq!(full.len(), 65536);
+    assert_eq!(full[0xf000..], [0x5a; 4096]);
+
+    let firmware_file = test_filename!("firmware.hex");
+    let split_bootloader_file = test_filename!("split_bootloader.bin");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("split")
+        .args(&["--device", "nuphy-air60"])
+        .arg(&full_file)
+        .arg(&firmware_file)
+        .arg(&split_bootloader_file)
+        .assert();
+
+    assert.success();
+
+    // the firmware keeps the JTAG layout, same as converting it directly
+    let computed_md5 = md5::compute(fs::read(&firmware_file).unwrap());
+    assert_eq!(
+        format!("{:x}", computed_md5),
+        "3bbd99f81678fc11fdf1ba9eaaac2bd1"
+    );
+    assert_eq!(fs::read(&split_bootloader_file).unwrap(), [0x5a; 4096]);
+}
+
+#[test]
+#[serial]
+fn test_merge_bootloader_starting_at_zero() {
+    // bootloaders read by older versions were written with addresses starting at 0x0000
+    let records: Vec<ihex::Record> = (0..4096)
+        .step_by(16)
+        .map(|offset| ihex::Record::Data {
+            offset,
+            value: vec![0x5a; 16],
+        })
+        .chain([ihex::Record::EndOfFile])
+        .collect();
+    let bootloader_file = test_filename!("bootloader.hex");
+    fs::write(
+        &bootloader_file,
+        ihex::create_object_file_representation(&records).unwrap(),
+    )
+    .unwrap();
+
+    let full_file = test_filename!("full.bin");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("merge")
+        .args(&["--device", "nuphy-air60"])
+        .arg(get_fixture_path("nuphy-air60_smk.hex"))
+        .arg(&bootloader_file)
+        .arg(&full_file)
+        .assert();
+    assert.success();
+
+    let full = fs::read(&full_file).unwrap();
+    assert_eq!(full.len(), 65536);
+    assert_eq!(full[0xf000..], [0x5a; 4096]);
+}
+
+#[test]
+#[serial]
+fn test_convert_rejects_transport_args() {
+    let input_file = get_fixture_path("nuphy-air60_smk.hex");
+    let output_file = test_filename!("hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("convert")
+        .args(&["--device", "nuphy-air60"])
+        .args(&["--direction", "to_jtag"])
+        .args(&["--simulate", &input_file])
+        .arg(&input_file)
+        .arg(&output_file)
+        .assert();
+
+    assert.failure().stderr(predicates::str::contains(
+        "unexpected argument '--simulate'",
+    ));
+}
@@ -0,0 +1,94 @@
+use std::fs;
+
+use assert_cmd::Command;
+use predicates::prelude::*;
+
+#[macro_use]
+pub mod common;
+
+use common::get_fixture_path;
+
+#[test]
+fn test_extract_pcap() {
+    let input_file = get_fixture_path("nuphy-air60_smk_usbmon.pcap");
+    let output_file = test_filename!("bin");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("extract-pcap")
+        .args(["--device", "nuphy-air60"])
+        .arg(&input_file)
+        .arg(&output_file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "MD5: 662c8707c4be0e0712e30336b0e7cfd1",
+    ));
+
+    assert_eq!(
+        fs::read(&output_file).unwrap(),
+        fs::read(get_fixture_path("nuphy-air60_smk.bin")).unwrap()
+    );
+}
+
+#[test]
+fn test_extract_pcap_invalid_capture() {
+    let input_file = get_fixture_path("nuphy-air60_smk.bin");
+    let output_file = test_filename!("bin");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("extract-pcap")
+        .args(["--device", "nuphy-air60"])
+        .arg(&input_file)
+        .arg(&output_file)
+        .assert();
+    assert
+        .failure()
+        .stderr(predicates::str::contains("Not a pcap or pcapng capture"));
+}
+
+#[test]
+fn test_extract_updater() {
+    let input_file = get_fixture_path("nuphy-air60_updater.exe");
+    let output_dir = test_filename!("dir");
+    fs::create_dir_all(&output_dir).unwrap();
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("extract-updater")
+        .args(["--output_dir", &output_dir])
+        .arg(&input_file)
+        .assert();
+    assert.success().stdout(predicates::str::contains(
+        "#1: resource 10/101/1033 @ 0x0000, sh68f90 (61440 bytes), MD5: 662c8707c4be0e0712e30336b0e7cfd1",
+    ));
+
+    assert_eq!(
+        fs::read(format!("{}/candidate-1-sh68f90.bin", output_dir)).unwrap(),
+        fs::read(get_fixture_path("nuphy-air60_smk.bin")).unwrap()
+    );
+}
+
+#[test]
+fn test_extract_updater_without_resource_directory() {
+    // clear the resource data directory so the firmware is only found by scanning sections
+    let mut executable = fs::read(get_fixture_path("nuphy-air60_updater.exe")).unwrap();
+    let pe_offset = u32::from_le_bytes(executable[0x3c..0x40].try_into().unwrap()) as usize;
+    let resource_directory = pe_offset + 24 + 96 + 2 * 8;
+    executable[resource_directory..resource_directory + 8].fill(0);
+    let input_file = test_filename!("exe");
+    fs::write(&input_file, executable).unwrap();
+
+    let output_dir = test_filename!("dir");
+    fs::create_dir_all(&output_dir).unwrap();
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("extract-updater")
+        .args(["--output_dir", &output_dir])
+        .arg(&input_file)
+        .assert();
+    assert
+        .success()
+        .stdout(predicates::str::contains(
+            "#1: section .rsrc @ 0x0060, sh68f90 (61440 bytes), MD5: 662c8707c4be0e0712e30336b0e7cfd1",
+        ))
+        .stdout(predicates::str::contains("sh68f881").not())
+        .stdout(predicates::str::contains("#2").not());
+}
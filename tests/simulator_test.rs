@@ -0,0 +1,1056 @@
+use std::fs;
+
+use assert_cmd::Command;
+use predicates::prelude::*;
+
+#[macro_use]
+pub mod common;
+
+use common::get_fixture_path;
+
+fn simulated_flash(file: &str) -> String {
+    fs::copy(get_fixture_path("nuphy-air60_smk_flash.bin"), file).unwrap();
+    file.to_string()
+}
+
+#[test]
+fn test_simulated_read() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let file = test_filename!("hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg(&file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "MD5: 662c8707c4be0e0712e30336b0e7cfd1",
+    ));
+
+    let computed_md5 = md5::compute(fs::read(&file).unwrap());
+    assert_eq!(
+        format!("{:x}", computed_md5),
+        "6594e5a1ab671deb40f36483a84ad61f"
+    );
+}
+
+#[test]
+fn test_simulated_read_bootloader() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let file = test_filename!("bin");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--section", "bootloader"])
+        .args(["--simulate", &flash_file])
+        .arg(&file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "MD5: 620f0b67a91f7f74151bc5be745b7110",
+    ));
+}
+
+#[test]
+fn test_simulated_write() {
+    let flash_file = test_filename!("flash.bin");
+    fs::write(&flash_file, vec![0xff; 65536]).unwrap();
+
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg(&fixture_file)
+        .assert();
+    assert.success();
+
+    let flash = fs::read(&flash_file).unwrap();
+    let fixture_flash = fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap();
+    assert_eq!(flash[..0xf000], fixture_flash[..0xf000]);
+    assert_eq!(flash[0xf000..], [0xff; 0x1000]);
+}
+
+#[test]
+fn test_simulated_write_and_readback() {
+    let flash_file = test_filename!("flash.bin");
+    fs::write(&flash_file, vec![0x00; 65536]).unwrap();
+
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut write_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = write_cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg(&fixture_file)
+        .assert();
+    assert.success();
+
+    let output_file = test_filename!("hex");
+    let mut read_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = read_cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg(&output_file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "MD5: 662c8707c4be0e0712e30336b0e7cfd1",
+    ));
+
+    let computed_md5 = md5::compute(fs::read(&output_file).unwrap());
+    assert_eq!(
+        format!("{:x}", computed_md5),
+        "6594e5a1ab671deb40f36483a84ad61f"
+    );
+}
+
+#[test]
+fn test_simulated_write_with_fault() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--fault", "drop@19"])
+        .arg("--no_backup")
+        .args(["--page_retries", "0"])
+        .arg(&fixture_file)
+        .assert();
+    assert
+        .failure()
+        .stderr(predicates::str::contains("Injected fault: report dropped"));
+}
+
+#[test]
+fn test_fault_requires_simulated_device() {
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--fault", "corrupt@5"])
+        .arg(&fixture_file)
+        .assert();
+    assert
+        .failure()
+        .stderr(predicates::str::contains(
+            "the following required arguments were not provided",
+        ))
+        .stderr(predicates::str::contains("--simulate <FLASH_FILE>"))
+        .stderr(predicates::str::contains("Erasing").not());
+}
+
+#[test]
+fn test_simulated_write_with_fault_retry() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--fault", "drop@19"])
+        .arg("--no_backup")
+        .arg(&fixture_file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "Page @ 0x8000 failed: hidapi error: Injected fault: report dropped. Retrying in 100ms (1/3)",
+    ));
+
+    let flash = fs::read(&flash_file).unwrap();
+    let fixture_flash = fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap();
+    assert_eq!(flash, fixture_flash);
+}
+
+#[test]
+fn test_simulated_read_trace_replay() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let trace_file = test_filename!("trace");
+    let mut record_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = record_cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--trace", &trace_file])
+        .arg(test_filename!("recorded.bin"))
+        .assert();
+    assert.success();
+
+    let trace = fs::read_to_string(&trace_file).unwrap();
+    assert!(trace.contains(" SET 05 52 00 00 00 00"));
+
+    let mut replay_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = replay_cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--replay", &trace_file])
+        .arg(test_filename!("replayed.bin"))
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "MD5: 662c8707c4be0e0712e30336b0e7cfd1",
+    ));
+}
+
+#[test]
+fn test_replay_after_isp_switch() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let trace_file = test_filename!("trace");
+    let mut record_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = record_cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--trace", &trace_file])
+        .arg(test_filename!("recorded.bin"))
+        .assert();
+    assert.success();
+
+    // a trace of a real device starts with the switch into ISP mode
+    let trace = fs::read_to_string(&trace_file).unwrap();
+    fs::write(
+        &trace_file,
+        format!(
+            "0.000000 SET 05 75 00 00 00 00 ! hidapi error: hid_error is not implemented yet\n{}",
+            trace
+        ),
+    )
+    .unwrap();
+
+    let mut replay_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = replay_cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--replay", &trace_file])
+        .arg(test_filename!("replayed.bin"))
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "MD5: 662c8707c4be0e0712e30336b0e7cfd1",
+    ));
+}
+
+#[test]
+fn test_replay_longer_session() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let trace_file = test_filename!("trace");
+    let mut record_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = record_cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--trace", &trace_file])
+        .arg(test_filename!("recorded.bin"))
+        .assert();
+    assert.success();
+
+    let mut trace = fs::read_to_string(&trace_file).unwrap();
+    trace.push_str("9.000000 SET 05 45 00 00 00 00\n");
+    fs::write(&trace_file, trace).unwrap();
+
+    let mut replay_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = replay_cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--replay", &trace_file])
+        .arg(test_filename!("replayed.bin"))
+        .assert();
+    assert
+        .failure()
+        .stderr(predicates::str::contains("1 more entries were recorded"))
+        .stderr(predicates::str::contains("Successfully read").not());
+}
+
+#[test]
+fn test_replay_mismatch() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let trace_file = test_filename!("trace");
+    let mut record_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = record_cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--trace", &trace_file])
+        .arg(test_filename!("bin"))
+        .assert();
+    assert.success();
+
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut replay_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = replay_cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--replay", &trace_file])
+        .arg(&fixture_file)
+        .assert();
+    assert
+        .failure()
+        .stderr(predicates::str::contains("Trace replay: report mismatch"));
+}
+
+#[test]
+fn test_simulated_read_range() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let file = test_filename!("bin");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--start", "0xeffb"])
+        .args(["--length", "0x10"])
+        .args(["--simulate", &flash_file])
+        .arg(&file)
+        .assert();
+    assert.success();
+
+    let flash = fs::read(&flash_file).unwrap();
+    let range = fs::read(&file).unwrap();
+    // the LJMP slot reads back as zeroes, the bootloader as is
+    assert_eq!(range[..5], [0x00; 5]);
+    assert_eq!(range[5..], flash[0xf000..0xf00b]);
+}
+
+#[test]
+fn test_simulated_read_range_overflow() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let file = test_filename!("bin");
+    for dry_run in [false, true] {
+        let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+        cmd.arg("read")
+            .args(["--device", "nuphy-air60"])
+            .args(["--start", "0xfffffffffffffff0"])
+            .args(["--length", "0x20"])
+            .args(["--simulate", &flash_file]);
+        if dry_run {
+            cmd.arg("--dry_run");
+        }
+        let assert = cmd.arg(&file).assert();
+        assert.failure().stderr(predicates::str::contains(
+            "Address range 0xfffffffffffffff0-0xffffffffffffffff is outside of flash (size 0x10000)",
+        ));
+    }
+}
+
+#[test]
+fn test_simulated_verify() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("verify")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg(&fixture_file)
+        .assert();
+    assert
+        .success()
+        .stderr(predicates::str::contains("Flash contents match"));
+}
+
+#[test]
+fn test_simulated_verify_mismatch() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let mut flash = fs::read(&flash_file).unwrap();
+    flash[0x1000..0x1010].iter_mut().for_each(|b| *b ^= 0xff);
+    flash[0x2000] ^= 0xff;
+    fs::write(&flash_file, flash).unwrap();
+
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("verify")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg(&fixture_file)
+        .assert();
+    assert
+        .failure()
+        .stderr(predicates::str::contains(
+            "Mismatch @ 0x1000-0x100f (16 bytes)",
+        ))
+        .stderr(predicates::str::contains(
+            "Mismatch @ 0x2000-0x2000 (1 bytes)",
+        ))
+        .stderr(predicates::str::contains("Firmware Mismatch @ 0x1000"));
+}
+
+#[test]
+fn test_simulated_verify_jtag_layout() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let fixture_file = get_fixture_path("nuphy-air60_smk_jtag.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("verify")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg(&fixture_file)
+        .assert();
+    assert
+        .success()
+        .stderr(predicates::str::contains(
+            "Payload has the JTAG layout, converting it to the ISP layout",
+        ))
+        .stderr(predicates::str::contains("Flash contents match"));
+}
+
+#[test]
+fn test_simulated_verify_ajmp_reset_vector() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+
+    let mut firmware = fs::read(get_fixture_path("nuphy-air60_smk.bin")).unwrap();
+    firmware[0..2].copy_from_slice(&[0x01, 0x71]);
+    let input_file = test_filename!("bin");
+    fs::write(&input_file, firmware).unwrap();
+
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("verify")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg(&input_file)
+        .assert();
+    assert
+        .success()
+        .stderr(predicates::str::contains(
+            "Rewriting the reset vector AJMP 0x0071 as LJMP 0x0071",
+        ))
+        .stderr(predicates::str::contains("Flash contents match"));
+}
+
+#[test]
+fn test_simulated_verify_too_large() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+
+    let mut firmware = fs::read(get_fixture_path("nuphy-air60_smk.bin")).unwrap();
+    firmware.extend_from_slice(&[0xaa; 0x10]);
+    let input_file = test_filename!("bin");
+    fs::write(&input_file, firmware).unwrap();
+
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("verify")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg(&input_file)
+        .assert();
+    assert
+        .failure()
+        .stderr(predicates::str::contains(
+            "Payload is 61456 bytes, larger than the firmware section (61440 bytes)",
+        ))
+        .stderr(predicates::str::contains("Flash contents match").not());
+}
+
+#[test]
+fn test_simulated_erase_and_enable() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let mut erase_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = erase_cmd
+        .arg("erase")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg("--force")
+        .assert();
+    assert.success();
+
+    let flash = fs::read(&flash_file).unwrap();
+    assert_eq!(flash[0..3], [0x02, 0xf0, 0x00]);
+    assert!(flash[3..0xf000].iter().all(|b| *b == 0x00));
+
+    let mut enable_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = enable_cmd
+        .arg("enable")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .assert();
+    assert.success();
+
+    let flash = fs::read(&flash_file).unwrap();
+    assert_eq!(flash[0xeffb], 0x02);
+}
+
+#[test]
+fn test_simulated_reboot() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("reboot")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .assert();
+    assert
+        .success()
+        .stderr(predicates::str::contains("Rebooting..."));
+
+    assert_eq!(
+        fs::read(&flash_file).unwrap(),
+        fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap()
+    );
+}
+
+#[test]
+fn test_simulated_blank_check() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("blank-check")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .assert();
+    assert
+        .failure()
+        .stderr(predicates::str::contains("Flash is not blank @ 0x0003"));
+}
+
+#[test]
+fn test_simulated_blank_check_after_erase() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    cmd.arg("erase")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg("--force")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("blank-check")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .assert();
+    assert
+        .success()
+        .stderr(predicates::str::contains("Firmware section is blank"))
+        .stderr(predicates::str::contains("Enabling firmware...").not())
+        .stderr(predicates::str::contains("Rebooting...").not());
+
+    // the device stays in ISP mode, the firmware was not enabled
+    let flash = fs::read(&flash_file).unwrap();
+    assert_eq!(flash[0..3], [0x02, 0xf0, 0x00]);
+    assert_eq!(flash[0xeffb], 0x00);
+}
+
+#[test]
+fn test_simulated_write_verify_erase() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg("--verify_erase")
+        .arg(&fixture_file)
+        .assert();
+    assert
+        .success()
+        .stderr(predicates::str::contains("Verifying erase..."));
+}
+
+#[test]
+fn test_simulated_read_passes() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let file = test_filename!("bin");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--passes", "3"])
+        .args(["--simulate", &flash_file])
+        .arg(&file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "MD5: 662c8707c4be0e0712e30336b0e7cfd1",
+    ));
+}
+
+#[test]
+fn test_simulated_write_resume() {
+    let flash_file = test_filename!("flash.bin");
+    fs::write(&flash_file, vec![0x00; 65536]).unwrap();
+    let journal_file = format!("{}.journal", flash_file);
+
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut write_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = write_cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--fault", "disconnect@19"])
+        .arg("--no_backup")
+        .arg(&fixture_file)
+        .assert();
+    assert.failure();
+    assert!(fs::read_to_string(&journal_file)
+        .unwrap()
+        .contains("last_page: 15"));
+
+    let mut resume_cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = resume_cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg("--resume")
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "interrupted after page 15, restarting from erase",
+    ));
+
+    assert!(fs::metadata(&journal_file).is_err());
+    let flash = fs::read(&flash_file).unwrap();
+    let fixture_flash = fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap();
+    assert_eq!(flash, fixture_flash);
+}
+
+#[test]
+fn test_simulated_write_resume_without_journal() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg("--resume")
+        .assert();
+    assert
+        .failure()
+        .stderr(predicates::str::contains("No interrupted write found"));
+}
+
+#[test]
+fn test_simulated_write_backup() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let backup_dir = test_filename!("backup");
+    fs::create_dir_all(&backup_dir).unwrap();
+
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--backup_dir", &backup_dir])
+        .arg(&fixture_file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "(MD5: 662c8707c4be0e0712e30336b0e7cfd1)",
+    ));
+
+    let backups: Vec<_> = fs::read_dir(&backup_dir).unwrap().collect();
+    assert_eq!(backups.len(), 1);
+    assert_eq!(
+        fs::read(backups[0].as_ref().unwrap().path()).unwrap(),
+        fs::read(get_fixture_path("nuphy-air60_smk.bin")).unwrap()
+    );
+}
+
+#[test]
+fn test_simulated_write_auto_rollback() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+
+    let mut firmware = fs::read(get_fixture_path("nuphy-air60_smk.bin")).unwrap();
+    firmware[0x100] ^= 0xff;
+    let input_file = test_filename!("bin");
+    fs::write(&input_file, firmware).unwrap();
+
+    // the backup takes 32 reports, the corrupted report is the second page of the write
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--fault", "corrupt@36"])
+        .arg("--auto_rollback")
+        .arg(&input_file)
+        .assert();
+    assert
+        .failure()
+        .stderr(predicates::str::contains(
+            "Successfully rolled back to the backup",
+        ))
+        .stderr(predicates::str::contains("Firmware Mismatch @ 0x0fff"));
+
+    assert_eq!(
+        fs::read(&flash_file).unwrap(),
+        fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap()
+    );
+}
+
+#[test]
+fn test_simulated_write_preserve() {
+    let flash_file = test_filename!("flash.bin");
+    let mut flash = fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap();
+    flash[0xe000..0xe100].fill(0x5a);
+    fs::write(&flash_file, &flash).unwrap();
+
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--preserve", "0xe000-0xe0ff"])
+        .arg("--no_backup")
+        .arg(&fixture_file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "Verified preserved range 0xe000-0xe0ff",
+    ));
+
+    assert_eq!(fs::read(&flash_file).unwrap(), flash);
+}
+
+#[test]
+fn test_simulated_write_preserve_overlap() {
+    let flash_file = test_filename!("flash.bin");
+    fs::write(&flash_file, vec![0x00; 65536]).unwrap();
+
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--preserve", "0x1000-0x10ff"])
+        .args(["--preserve", "0xe000-0xefff"])
+        .arg(&fixture_file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "Warning: preserved range 0x1000-0x10ff overlaps data in the payload",
+    ));
+
+    let flash = fs::read(&flash_file).unwrap();
+    assert_eq!(flash[0x1000..0x1100], [0x00; 0x100]);
+}
+
+#[test]
+fn test_simulated_write_preserve_out_of_range() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--preserve", "0xf000-0xf0ff"])
+        .arg(&fixture_file)
+        .assert();
+    assert.failure().stderr(predicates::str::contains(
+        "Address range 0xf000-0xf100 is outside of flash (size 0xf000)",
+    ));
+
+    assert_eq!(
+        fs::read(&flash_file).unwrap(),
+        fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap()
+    );
+}
+
+#[test]
+fn test_simulated_write_if_changed() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+
+    // erasing is the first report after the 32 read reports, so any write would fail
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--fault", "disconnect@33"])
+        .arg("--if_changed")
+        .arg("--no_backup")
+        .arg(&fixture_file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "Device already contains the payload (MD5: 662c8707c4be0e0712e30336b0e7cfd1), skipping write",
+    ));
+
+    let mut firmware = fs::read(get_fixture_path("nuphy-air60_smk.bin")).unwrap();
+    firmware[0x100] ^= 0xff;
+    let input_file = test_filename!("bin");
+    fs::write(&input_file, &firmware).unwrap();
+
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg("--if_changed")
+        .arg("--no_backup")
+        .arg(&input_file)
+        .assert();
+    assert
+        .success()
+        .stderr(predicates::str::contains(
+            "Device contents differ from the payload",
+        ))
+        .stderr(predicates::str::contains("Successfully wrote"));
+
+    assert_eq!(fs::read(&flash_file).unwrap()[0x100], firmware[0x100]);
+}
+
+#[test]
+fn test_simulated_write_dry_run() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let trace_file = test_filename!("trace");
+
+    let mut firmware = fs::read(get_fixture_path("nuphy-air60_smk.bin")).unwrap();
+    firmware[0x100] ^= 0xff;
+    let input_file = test_filename!("bin");
+    fs::write(&input_file, firmware).unwrap();
+
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .args(["--trace", &trace_file])
+        .arg("--dry_run")
+        .arg(&input_file)
+        .assert();
+    assert
+        .success()
+        .stderr(predicates::str::contains(
+            "Dry run, no feature report will be sent",
+        ))
+        .stderr(predicates::str::contains(
+            "Erase the firmware section, the bootloader is kept and the reset vector jumps to ISP (report 05 45 00 00 00 00)",
+        ))
+        .stderr(predicates::str::contains(
+            "Write 30 pages of 2048 bytes @ 0x0000, 0x0800,",
+        ))
+        .stderr(predicates::str::contains(
+            "(report 06 77), retrying a failed page up to 3 times, recording each written page in the journal",
+        ))
+        .stderr(predicates::str::contains(
+            "Reboot into the firmware (report 05 5a 00 00 00 00)",
+        ))
+        .stderr(predicates::str::contains(format!(
+            "Remove journal {}.journal",
+            flash_file
+        )));
+
+    assert!(fs::metadata(&trace_file).is_err());
+    assert_eq!(
+        fs::read(&flash_file).unwrap(),
+        fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap()
+    );
+}
+
+#[test]
+fn test_simulated_write_invalid_payload() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+
+    let mut firmware = fs::read(get_fixture_path("nuphy-air60_smk.bin")).unwrap();
+    firmware[0xeffc..0xeffe].copy_from_slice(&[0x12, 0x34]);
+    let input_file = test_filename!("bin");
+    fs::write(&input_file, firmware).unwrap();
+
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg(&input_file)
+        .assert();
+    assert
+        .failure()
+        .stderr(predicates::str::contains(
+            "Error: Payload has data at 0xeffc-0xeffd, which is overwritten with the reset vector address",
+        ))
+        .stderr(predicates::str::contains(
+            "use --skip_validation to write it anyway",
+        ));
+
+    assert_eq!(
+        fs::read(&flash_file).unwrap(),
+        fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap()
+    );
+
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg("--skip_validation")
+        .arg("--no_backup")
+        .arg(&input_file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "Warning: Payload has data at 0xeffc-0xeffd",
+    ));
+}
+
+#[test]
+fn test_simulated_write_reset_vector_into_bootloader() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+
+    let mut firmware = fs::read(get_fixture_path("nuphy-air60_smk.bin")).unwrap();
+    firmware[0..3].copy_from_slice(&[0x02, 0xf0, 0x00]);
+    let input_file = test_filename!("bin");
+    fs::write(&input_file, firmware).unwrap();
+
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg("--no_backup")
+        .arg(&input_file)
+        .assert();
+    assert.failure().stderr(predicates::str::contains(
+        "Error: Reset vector jumps to 0xf000, outside of the firmware section",
+    ));
+
+    assert_eq!(
+        fs::read(&flash_file).unwrap(),
+        fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap()
+    );
+}
+
+#[test]
+fn test_simulated_write_ajmp_reset_vector() {
+    let flash_file = test_filename!("flash.bin");
+    fs::write(&flash_file, vec![0x00; 65536]).unwrap();
+
+    let mut firmware = fs::read(get_fixture_path("nuphy-air60_smk.bin")).unwrap();
+    assert_eq!(firmware[0..3], [0x02, 0x00, 0x71]);
+    firmware[0..2].copy_from_slice(&[0x01, 0x71]);
+    let input_file = test_filename!("bin");
+    fs::write(&input_file, firmware).unwrap();
+
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg(&input_file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "Rewriting the reset vector AJMP 0x0071 as LJMP 0x0071",
+    ));
+
+    let flash = fs::read(&flash_file).unwrap();
+    let fixture_flash = fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap();
+    assert_eq!(flash[..0xf000], fixture_flash[..0xf000]);
+}
+
+#[test]
+fn test_simulated_write_jtag_layout() {
+    let flash_file = test_filename!("flash.bin");
+    fs::write(&flash_file, vec![0x00; 65536]).unwrap();
+
+    let fixture_file = get_fixture_path("nuphy-air60_smk_jtag.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .args(["--simulate", &flash_file])
+        .arg(&fixture_file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "Payload has the JTAG layout, converting it to the ISP layout",
+    ));
+
+    let flash = fs::read(&flash_file).unwrap();
+    let fixture_flash = fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap();
+    assert_eq!(flash[..0xf000], fixture_flash[..0xf000]);
+}
+
+#[test]
+fn test_simulated_read_physical_layout() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let file = test_filename!("bin");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--layout", "physical"])
+        .args(["--simulate", &flash_file])
+        .arg(&file)
+        .assert();
+    assert.success();
+
+    // the simulated flash holds the physical contents, same as a JTAG read
+    assert_eq!(
+        fs::read(&file).unwrap(),
+        fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap()
+    );
+}
+
+#[test]
+fn test_simulated_read_layout_with_range() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let file = test_filename!("bin");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--layout", "isp"])
+        .args(["--start", "0x100", "--length", "0x10"])
+        .args(["--simulate", &flash_file])
+        .arg(&file)
+        .assert();
+    assert.success();
+    assert_eq!(
+        fs::read(&file).unwrap(),
+        fs::read(get_fixture_path("nuphy-air60_smk.bin")).unwrap()[0x100..0x110]
+    );
+
+    for range_args in [["--start", "0x100"], ["--section", "firmware"]] {
+        let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+        let assert = cmd
+            .arg("read")
+            .args(["--device", "nuphy-air60"])
+            .args(["--layout", "physical"])
+            .args(range_args)
+            .args(["--simulate", &flash_file])
+            .arg(&file)
+            .assert();
+        assert.failure().stderr(predicates::str::contains(format!(
+            "the argument '--layout physical' cannot be used with '{}'",
+            range_args[0]
+        )));
+    }
+}
+
+#[test]
+fn test_simulated_read_bootloader_ihex_address() {
+    let flash_file = simulated_flash(&test_filename!("flash.bin"));
+    let bootloader_file = test_filename!("bootloader.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--section", "bootloader"])
+        .args(["--simulate", &flash_file])
+        .arg(&bootloader_file)
+        .assert();
+    assert.success();
+
+    let ihex = fs::read_to_string(&bootloader_file).unwrap();
+    assert!(ihex.starts_with(":020000040000FA\n:10F0000000000000000000000000000000000000"));
+
+    // the bootloader is loaded back from its real address
+    let full_file = test_filename!("full.bin");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("merge")
+        .args(["--device", "nuphy-air60"])
+        .arg(get_fixture_path("nuphy-air60_smk.hex"))
+        .arg(&bootloader_file)
+        .arg(&full_file)
+        .assert();
+    assert.success();
+
+    assert_eq!(
+        fs::read(&full_file).unwrap(),
+        fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap()
+    );
+}
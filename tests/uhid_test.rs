@@ -0,0 +1,116 @@
+//! These tests require write access to `/dev/uhid` and a build with the `uhid` feature:
+//! `cargo test --no-default-features --features uhid --test uhid_test`
+#![cfg(all(target_os = "linux", feature = "uhid"))]
+
+use std::{
+    fs,
+    process::{Child, Command as StdCommand},
+    thread,
+    time::Duration,
+};
+
+use assert_cmd::{cargo::cargo_bin, Command};
+use serial_test::serial;
+
+#[macro_use]
+pub mod common;
+
+use common::get_fixture_path;
+
+struct UhidKeyboard(Child);
+
+impl UhidKeyboard {
+    fn spawn(flash_file: &str) -> Self {
+        let child = StdCommand::new(cargo_bin("sinowealth-kb-uhid"))
+            .args(["--device", "nuphy-air60"])
+            .arg(flash_file)
+            .spawn()
+            .unwrap();
+        // give udev some time to pick up the new device
+        thread::sleep(Duration::from_secs(1));
+        Self(child)
+    }
+}
+
+impl Drop for UhidKeyboard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+#[serial]
+fn test_uhid_read() {
+    let flash_file = test_filename!("flash.bin");
+    fs::copy(get_fixture_path("nuphy-air60_smk_flash.bin"), &flash_file).unwrap();
+    let _keyboard = UhidKeyboard::spawn(&flash_file);
+
+    let file = test_filename!("bin");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .arg(&file)
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "MD5: 662c8707c4be0e0712e30336b0e7cfd1",
+    ));
+}
+
+#[test]
+#[serial]
+fn test_uhid_write() {
+    let flash_file = test_filename!("flash.bin");
+    fs::write(&flash_file, vec![0x00; 65536]).unwrap();
+    let keyboard = UhidKeyboard::spawn(&flash_file);
+
+    let fixture_file = get_fixture_path("nuphy-air60_smk.hex");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("write")
+        .args(["--device", "nuphy-air60"])
+        .arg(&fixture_file)
+        .assert();
+    assert.success();
+    drop(keyboard);
+
+    let flash = fs::read(&flash_file).unwrap();
+    let fixture_flash = fs::read(get_fixture_path("nuphy-air60_smk_flash.bin")).unwrap();
+    assert_eq!(flash, fixture_flash);
+}
+
+#[test]
+#[serial]
+fn test_uhid_trace_replay() {
+    let flash_file = test_filename!("flash.bin");
+    fs::copy(get_fixture_path("nuphy-air60_smk_flash.bin"), &flash_file).unwrap();
+    let keyboard = UhidKeyboard::spawn(&flash_file);
+
+    let trace_file = test_filename!("trace");
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--trace", &trace_file])
+        .arg(test_filename!("recorded.bin"))
+        .assert();
+    assert.success();
+    drop(keyboard);
+
+    // the switch into ISP mode is sent to the keyboard before the bootloader shows up
+    let trace = fs::read_to_string(&trace_file).unwrap();
+    let first_entry = trace.lines().find(|line| !line.starts_with('#')).unwrap();
+    assert!(first_entry.contains(" SET 05 75 00 00 00 00"));
+
+    let mut cmd = Command::cargo_bin("sinowealth-kb-tool").unwrap();
+    let assert = cmd
+        .arg("read")
+        .args(["--device", "nuphy-air60"])
+        .args(["--replay", &trace_file])
+        .arg(test_filename!("replayed.bin"))
+        .assert();
+    assert.success().stderr(predicates::str::contains(
+        "MD5: 662c8707c4be0e0712e30336b0e7cfd1",
+    ));
+}